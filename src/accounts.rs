@@ -0,0 +1,359 @@
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::fs::{self, File};
+use std::io::{self, prelude::*};
+use std::num::NonZeroU32;
+use std::path::{Path, PathBuf};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::os::unix::net::UnixStream;
+use std::sync::mpsc;
+use ring::pbkdf2;
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::names::skeleton;
+
+/// Shortest password accepted when registering, in characters.
+pub const MIN_PASSWORD_LEN: usize = 8;
+/// PBKDF2 iterations for new password hashes. Each account records its own count, so this can be raised
+/// without invalidating existing accounts.
+const HASH_ITERATIONS: NonZeroU32 = NonZeroU32::new(100_000).unwrap();
+const SALT_LEN: usize = 16;
+
+/// A registered name, and what is needed to check its password.
+#[derive(Clone)]
+pub struct Account {
+    /// The name as registered.
+    name: String,
+    iterations: NonZeroU32,
+    salt: Vec<u8>,
+    /// PBKDF2-HMAC-SHA256 of the password.
+    hash: [u8; 32],
+}
+
+impl Account {
+    /// An account for name with password, hashed with a new random salt. Slow on purpose, like verify.
+    pub fn new(name: &str, password: &str) -> io::Result<Self> {
+        let mut salt = vec![0; SALT_LEN];
+        SystemRandom::new().fill(&mut salt).map_err(|_| io::Error::other("could not generate a salt"))?;
+        let hash = hash_password(password, &salt, HASH_ITERATIONS);
+        Ok(Account { name: name.into(), iterations: HASH_ITERATIONS, salt, hash })
+    }
+
+    /// The name as registered.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Whether password is the account's password. The hash is deliberately slow to compute.
+    pub fn verify(&self, password: &str) -> bool {
+        // in constant time, so how long it takes says nothing about how much of the hash matched
+        pbkdf2::verify(pbkdf2::PBKDF2_HMAC_SHA256, self.iterations, &self.salt, password.as_bytes(), &self.hash).is_ok()
+    }
+}
+
+/// Registered names, kept in a text file with one account per line: the name, the PBKDF2 iteration count,
+/// the salt and the hash (the last two in hex), separated by tabs.
+///
+/// The whole file is rewritten on each registration, to a temporary file that then replaces it,
+/// so a crash leaves either the old file or the new one.
+pub struct AccountStore {
+    path: PathBuf,
+    /// Keyed by the skeleton of the name, so that registering a name also registers its look-alikes.
+    accounts: HashMap<String, Account>,
+}
+
+impl AccountStore {
+    /// Loads the accounts in the file at path, if it exists.
+    /// Unlike a corrupt history log, a corrupt account file is an error: skipping a line would free up a name.
+    pub fn open(path: impl AsRef<Path>) -> io::Result<Self> {
+        let path = path.as_ref().to_owned();
+        let text = match fs::read_to_string(&path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let mut accounts = HashMap::new();
+        for (i, line) in text.lines().enumerate() {
+            let account = parse_account(line).ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidData, format!("{}:{}: invalid account", path.display(), i + 1))
+            })?;
+            accounts.insert(skeleton(&account.name), account);
+        }
+        Ok(AccountStore { path, accounts })
+    }
+
+    /// Whether name, or a name that looks just like it, is registered.
+    pub fn is_registered(&self, name: &str) -> bool {
+        self.accounts.contains_key(&skeleton(name))
+    }
+
+    /// The account registered as name, or as a name that looks just like it.
+    pub fn get(&self, name: &str) -> Option<&Account> {
+        self.accounts.get(&skeleton(name))
+    }
+
+    /// Durably registers account, whose name must not be registered yet.
+    pub fn add(&mut self, account: Account) -> io::Result<()> {
+        let key = skeleton(&account.name);
+        self.accounts.insert(key.clone(), account);
+        if let Err(e) = self.save() {
+            self.accounts.remove(&key);
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut accounts: Vec<&Account> = self.accounts.values().collect();
+        accounts.sort_by(|a, b| a.name.cmp(&b.name));
+        let mut text = String::new();
+        for account in accounts {
+            text += &format!("{}\t{}\t{}\t{}\n", account.name, account.iterations, to_hex(&account.salt), to_hex(&account.hash));
+        }
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut file = File::create(&temp_path)?;
+        file.write_all(text.as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+fn parse_account(line: &str) -> Option<Account> {
+    let mut fields = line.split('\t');
+    let account = Account {
+        name: fields.next().filter(|name| !name.is_empty())?.into(),
+        iterations: fields.next()?.parse().ok()?,
+        salt: from_hex(fields.next()?)?,
+        hash: from_hex(fields.next()?)?.try_into().ok()?,
+    };
+    if fields.next().is_some() {
+        return None;
+    }
+    Some(account)
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) || !hex.is_ascii() {
+        return None;
+    }
+    (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(&hex[i..i + 2], 16).ok()).collect()
+}
+
+/// Something slow to do with a password, for a PasswordHasher.
+pub enum PasswordJob {
+    /// Checking a login against the account it names, if there is one.
+    /// If there is not, the password is hashed anyway, so that how long it takes does not give that away.
+    Check(Option<Account>, String),
+    /// Making a new account.
+    Hash { name: String, password: String },
+}
+
+pub enum PasswordResult {
+    /// The account's name as registered, if the password was right.
+    Checked(Option<String>),
+    Hashed(io::Result<Account>),
+}
+
+/// Does PasswordJobs on a thread of its own, so that waiting for the hashes does not hold up the server.
+/// Each job is sent with a key, which its result comes back with.
+/// The hasher is readable (for poll) while there are results to collect.
+pub struct PasswordHasher<K> {
+    jobs: mpsc::Sender<(K, PasswordJob)>,
+    results: mpsc::Receiver<(K, PasswordResult)>,
+    /// The thread writes a byte to the other end after each result.
+    waker: UnixStream,
+    /// Jobs sent whose results have not been collected yet.
+    pending: usize,
+}
+
+impl<K: Send + 'static> PasswordHasher<K> {
+    /// Starts the thread, which stops once the hasher is dropped (after finishing the job it is on, if any).
+    pub fn new() -> io::Result<Self> {
+        let (jobs, job_rx) = mpsc::channel();
+        let (result_tx, results) = mpsc::channel();
+        let (waker, mut wake) = UnixStream::pair()?;
+        waker.set_nonblocking(true)?;
+        // if the socket is full, there are already unread wakeups
+        wake.set_nonblocking(true)?;
+        std::thread::Builder::new().name("password hasher".into()).spawn(move || {
+            for (key, job) in job_rx {
+                let result = match job {
+                    PasswordJob::Check(Some(account), password) => {
+                        PasswordResult::Checked(Some(account.name.clone()).filter(|_| account.verify(&password)))
+                    },
+                    PasswordJob::Check(None, password) => {
+                        hash_password(&password, &[0; SALT_LEN], HASH_ITERATIONS);
+                        PasswordResult::Checked(None)
+                    },
+                    PasswordJob::Hash { name, password } => PasswordResult::Hashed(Account::new(&name, &password)),
+                };
+                if result_tx.send((key, result)).is_err() {
+                    break;
+                }
+                let _ = wake.write(&[0]);
+            }
+        })?;
+        Ok(PasswordHasher { jobs, results, waker, pending: 0 })
+    }
+
+    pub fn send(&mut self, key: K, job: PasswordJob) {
+        // the thread only stops before the hasher is dropped if it panicked
+        self.jobs.send((key, job)).expect("the password hasher thread stopped");
+        self.pending += 1;
+    }
+
+    /// How many jobs are queued or being worked on, or done but not collected yet.
+    pub fn pending(&self) -> usize {
+        self.pending
+    }
+
+    /// The results of the jobs done so far (that were not collected already), without waiting for more.
+    pub fn results(&mut self) -> Vec<(K, PasswordResult)> {
+        let mut buf = [0; 64];
+        while let Ok(1..) = self.waker.read(&mut buf) {}
+        let results: Vec<_> = self.results.try_iter().collect();
+        self.pending -= results.len();
+        results
+    }
+}
+
+impl<K> AsRawFd for PasswordHasher<K> {
+    fn as_raw_fd(&self) -> RawFd {
+        self.waker.as_raw_fd()
+    }
+}
+
+/// PBKDF2-HMAC-SHA256 (RFC 8018) of password, a single 32-byte block of it.
+fn hash_password(password: &str, salt: &[u8], iterations: NonZeroU32) -> [u8; 32] {
+    let mut hash = [0; 32];
+    pbkdf2::derive(pbkdf2::PBKDF2_HMAC_SHA256, iterations, salt, password.as_bytes(), &mut hash);
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::util::poll_events;
+
+    fn iterations(count: u32) -> NonZeroU32 {
+        NonZeroU32::new(count).unwrap()
+    }
+
+    /// The name as registered, if name is registered and password is its password.
+    fn check<'a>(store: &'a AccountStore, name: &str, password: &str) -> Option<&'a str> {
+        store.get(name).filter(|account| account.verify(password)).map(Account::name)
+    }
+
+    /// An accounts file path for a test, unique to the test, with nothing there yet.
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("chatapp-accounts-{}-{}.txt", std::process::id(), name));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn hashes_match_known_values() {
+        // published PBKDF2-HMAC-SHA256 test vectors; hashes in existing account files must keep verifying
+        let cases = [
+            (1, "120fb6cffcf8b32c43e7225256c4f837a86548c92ccc35480805987cb70be17b"),
+            (2, "ae4d0c95af6b46d32d0adff928f06dd02a303f8ef3c251dfd6e2d85a95474c43"),
+            (4096, "c5e478d59288c841aa530db6845c4c8d962893a001ce4e11a4963873aa98134a"),
+        ];
+        for &(count, hash) in &cases {
+            assert_eq!(to_hex(&hash_password("password", b"salt", iterations(count))), hash);
+        }
+    }
+
+    #[test]
+    fn accounts_line_round_trips() {
+        let line = format!("alice\t3\t{}\t{}", to_hex(b"salt"), to_hex(&hash_password("password", b"salt", iterations(3))));
+        let account = parse_account(&line).unwrap();
+        assert_eq!((&*account.name, account.iterations.get(), &*account.salt), ("alice", 3, &b"salt"[..]));
+        let store = AccountStore {
+            path: PathBuf::new(),
+            accounts: std::iter::once((skeleton("alice"), account)).collect(),
+        };
+        assert_eq!(check(&store, "ALICE", "password"), Some("alice"));
+        assert_eq!(check(&store, "alice", "passwore"), None);
+        assert_eq!(check(&store, "bob", "password"), None);
+        assert!(store.is_registered("AL1CE"));
+        assert!(parse_account("alice\t3\tzz\t00").is_none());
+        assert!(parse_account("alice\t0\t00\t00").is_none());
+    }
+
+    #[test]
+    fn accounts_are_kept_in_the_file() {
+        let path = temp_path("round-trip");
+        let mut store = AccountStore::open(&path).unwrap();
+        assert!(!store.is_registered("alice"));
+        store.add(Account::new("alice", "correct horse").unwrap()).unwrap();
+        store.add(Account::new("bob", "battery staple").unwrap()).unwrap();
+        drop(store);
+
+        let store = AccountStore::open(&path).unwrap();
+        assert_eq!(check(&store, "alice", "correct horse"), Some("alice"));
+        assert_eq!(check(&store, "Bob", "battery staple"), Some("bob"));
+        assert_eq!(check(&store, "alice", "battery staple"), None);
+        let text = fs::read_to_string(&path).unwrap();
+        assert_eq!(text.lines().count(), 2);
+        assert!(text.starts_with("alice\t100000\t"));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn hashing_is_done_on_another_thread() {
+        let account = Account::new("alice", "correct horse").unwrap();
+        let mut hasher = PasswordHasher::new().unwrap();
+        hasher.send(1, PasswordJob::Check(Some(account.clone()), "correct horse".into()));
+        hasher.send(2, PasswordJob::Check(Some(account), "battery staple".into()));
+        hasher.send(3, PasswordJob::Check(None, "correct horse".into()));
+        hasher.send(4, PasswordJob::Hash { name: "bob".into(), password: "battery staple".into() });
+        assert_eq!(hasher.pending(), 4);
+        let mut results = vec![];
+        while results.len() < 4 {
+            poll_events(std::iter::once(((), hasher.as_raw_fd(), libc::POLLIN)), -1).unwrap();
+            results.extend(hasher.results());
+        }
+        assert_eq!(hasher.pending(), 0);
+        let results: HashMap<_, _> = results.into_iter().collect();
+        assert!(matches!(&results[&1], PasswordResult::Checked(Some(name)) if name == "alice"));
+        assert!(matches!(results[&2], PasswordResult::Checked(None)));
+        assert!(matches!(results[&3], PasswordResult::Checked(None)));
+        assert!(matches!(&results[&4], PasswordResult::Hashed(Ok(account)) if account.name() == "bob" && account.verify("battery staple")));
+    }
+
+    #[test]
+    fn corrupt_files_are_an_error() {
+        let path = temp_path("corrupt");
+        let line = format!("alice\t3\t{}\t{}\n", to_hex(b"salt"), to_hex(&hash_password("password", b"salt", iterations(3))));
+        fs::write(&path, line + "bob\t3\tsalt\n").unwrap();
+        let err = AccountStore::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(err.to_string(), format!("{}:2: invalid account", path.display()));
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn saving_replaces_the_file_whole() {
+        let path = temp_path("replace");
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        // left over from a crash while saving
+        fs::write(&temp_path, "garbage").unwrap();
+        let mut store = AccountStore::open(&path).unwrap();
+        store.add(Account::new("alice", "correct horse").unwrap()).unwrap();
+        assert!(!Path::new(&temp_path).exists());
+        assert!(AccountStore::open(&path).unwrap().is_registered("alice"));
+        fs::remove_file(&path).unwrap();
+
+        // a registration that could not be saved does not count
+        let mut store = AccountStore::open(path.join("no such directory")).unwrap();
+        assert!(store.add(Account::new("alice", "correct horse").unwrap()).is_err());
+        assert!(!store.is_registered("alice"));
+    }
+}
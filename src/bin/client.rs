@@ -0,0 +1,958 @@
+// Parts of this file adapted from https://github.com/fdehau/tui-rs/blob/v0.16.0/examples/user_input.rs
+// licensed by fdehau on GitHub and other tui-rs contributors under the MIT license
+
+use std::net::*;
+use std::io;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+use std::path::PathBuf;
+use std::sync::{mpsc, Arc};
+use rustls::pki_types::ServerName;
+
+#[macro_use]
+extern crate chatapp;
+use chatapp::Level;
+use chatapp::config::*;
+use chatapp::util::*;
+use chatapp::messages::*;
+use chatapp::sanitize::escape;
+use chatapp::tls::*;
+use chatapp::client::{Client, Event};
+
+/// How many older chat messages to ask for at a time when scrolling past the top.
+const HISTORY_PAGE_LEN: u32 = 50;
+/// Delay before the first attempt to reconnect, doubled after every failed attempt up to MAX_RECONNECT_DELAY.
+const MIN_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(60);
+
+/// What a line in the message history is, which decides how it is drawn.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// Chat messages and notes from the client itself.
+    Normal,
+    /// Direct messages to or from us.
+    Direct,
+    /// Users joining, leaving and being renamed.
+    Event,
+}
+
+#[derive(Debug, Clone)]
+struct HistoryLine {
+    kind: LineKind,
+    /// Milliseconds since the Unix epoch: assigned by the server for chat messages that have one,
+    /// otherwise when the line was added.
+    timestamp: u64,
+    /// Shown before the text, in a colour picked from the name, if the message came from someone.
+    sender: Option<Cow<'static, str>>,
+    text: Cow<'static, str>,
+}
+
+impl<T: Into<Cow<'static, str>>> From<T> for HistoryLine {
+    fn from(text: T) -> Self {
+        HistoryLine { kind: LineKind::Normal, timestamp: unix_millis(), sender: None, text: text.into() }
+    }
+}
+
+impl HistoryLine {
+    fn new(kind: LineKind, text: impl Into<Cow<'static, str>>) -> Self {
+        HistoryLine { kind, timestamp: unix_millis(), sender: None, text: text.into() }
+    }
+
+    fn chat(sender: impl Into<Cow<'static, str>>, text: impl Into<Cow<'static, str>>) -> Self {
+        HistoryLine { kind: LineKind::Normal, timestamp: unix_millis(), sender: Some(sender.into()), text: text.into() }
+    }
+
+    fn stamped(timestamp: u64, sender: impl Into<Cow<'static, str>>, text: impl Into<Cow<'static, str>>) -> Self {
+        HistoryLine { kind: LineKind::Normal, timestamp, sender: Some(sender.into()), text: text.into() }
+    }
+
+    /// Anything from the server is escaped here, so nothing it sends can act on the terminal.
+    fn to_list_item(&self) -> tui::widgets::ListItem<'_> {
+        use tui::style::{Style, Color, Modifier};
+        use tui::text::{Span, Spans};
+        let style = match self.kind {
+            LineKind::Normal => Style::default(),
+            LineKind::Direct => Style::default().fg(Color::Magenta),
+            LineKind::Event => Style::default().fg(Color::DarkGray).add_modifier(Modifier::ITALIC),
+        };
+        let mut spans = vec![Span::styled(format!("{} ", format_time(self.timestamp)), Style::default().fg(Color::DarkGray))];
+        if let Some(sender) = &self.sender {
+            spans.push(Span::styled(escape(sender), Style::default().fg(name_color(sender)).add_modifier(Modifier::BOLD)));
+            spans.push(Span::raw(": "));
+        }
+        spans.push(Span::styled(escape(&self.text), style));
+        tui::widgets::ListItem::new(Spans::from(spans))
+    }
+}
+
+/// Formats milliseconds since the Unix epoch as local "HH:MM" (or UTC, if the local time zone is unavailable).
+fn format_time(timestamp: u64) -> String {
+    let secs = (timestamp / 1000) as libc::time_t;
+    // Safety: tm is plain old data, and localtime_r only writes to it
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    if unsafe { libc::localtime_r(&secs, &mut tm) }.is_null() {
+        return format!("{:02}:{:02}", secs / 3600 % 24, secs / 60 % 60);
+    }
+    format!("{:02}:{:02}", tm.tm_hour, tm.tm_min)
+}
+
+/// What to tell the user when the server rejects a chat message.
+fn describe_chat_error(reason: ChatError) -> String {
+    match reason {
+        ChatError::InvalidUtf8 => "Message was rejected by the server: it was not valid UTF-8.".into(),
+        ChatError::TooLong => "Message was too long; the server is disconnecting you.".into(),
+        ChatError::Muted => "Message was not sent: you are muted.".into(),
+        ChatError::RateLimited => "Message was not sent: you are sending messages too fast.".into(),
+        ChatError::Other => "Message was rejected by the server.".into(),
+        ChatError::Unknown(reason) => format!("Message was rejected by the server (reason {}).", reason),
+    }
+}
+
+/// Why the server would not give us a name, to follow "Name request (...) denied: ".
+fn describe_name_denial(reason: NameDenial) -> String {
+    match reason {
+        NameDenial::AlreadyExists => "someone already has that name, or one that looks just like it".into(),
+        NameDenial::Empty => "names cannot be empty".into(),
+        NameDenial::TooLong => "that name is too long".into(),
+        NameDenial::InvalidCharacters => "that name has characters the server does not allow".into(),
+        NameDenial::Reserved => "that name is reserved".into(),
+        NameDenial::Registered => "that name is registered; if it is yours, use /login <name> <password>".into(),
+        NameDenial::Banned => "that name is banned".into(),
+        NameDenial::RateLimited => "you are changing your name too often; try again later".into(),
+        NameDenial::Other => "the server did not say why".into(),
+        NameDenial::Unknown(reason) => format!("unknown reason {}", reason),
+    }
+}
+
+/// Why the server would not register or log in to a name, to follow "Registration denied: " or "Login denied: ".
+fn describe_account_denial(reason: AccountDenial) -> String {
+    match reason {
+        AccountDenial::AlreadyRegistered => "that name is already registered".into(),
+        AccountDenial::InvalidName => "pick a name with /name first".into(),
+        AccountDenial::PasswordTooShort => "that password is too short".into(),
+        AccountDenial::InvalidCredentials => "wrong name or password".into(),
+        AccountDenial::Banned => "that name is banned".into(),
+        AccountDenial::RateLimited => "too many attempts; try again later".into(),
+        AccountDenial::Other => "the server did not say why".into(),
+        AccountDenial::Unknown(reason) => format!("unknown reason {}", reason),
+    }
+}
+
+/// Why the server would not carry out a /kick, /ban or /mute, to follow "Denied: ".
+fn describe_moderation_error(reason: ModerationError) -> String {
+    match reason {
+        ModerationError::NotOperator => "you are not an operator".into(),
+        ModerationError::NoSuchUser => "there is nobody with that name".into(),
+        ModerationError::Other => "the server did not say why".into(),
+        ModerationError::Unknown(reason) => format!("unknown reason {}", reason),
+    }
+}
+
+/// Splits the first space-separated word off s.
+fn next_word(s: &str) -> (&str, &str) {
+    let s = s.trim_start();
+    s.split_once(' ').unwrap_or((s, ""))
+}
+
+/// The input line as shown, with the password in a /register or /login command replaced by asterisks.
+fn mask_password(input_line: &str) -> Cow<'_, str> {
+    let visible_len = if input_line.starts_with("/register ") {
+        "/register ".len()
+    } else if let Some(args) = input_line.strip_prefix("/login ") {
+        // the name (and the space after it) stays visible
+        match args.find(' ') {
+            Some(space) => "/login ".len() + space + 1,
+            None => return input_line.into(),
+        }
+    } else {
+        return input_line.into();
+    };
+    let (visible, password) = input_line.split_at(visible_len);
+    format!("{}{}", visible, "*".repeat(password.chars().count())).into()
+}
+
+/// Picks a colour for a name, the same every time.
+fn name_color(name: &str) -> tui::style::Color {
+    use tui::style::Color;
+    const COLORS: [Color; 6] = [Color::Red, Color::Green, Color::Yellow, Color::Blue, Color::Magenta, Color::Cyan];
+    let hash = name.bytes().fold(0usize, |hash, b| hash.wrapping_mul(31).wrapping_add(b as usize));
+    COLORS[hash % COLORS.len()]
+}
+
+/// Sends msg, or tells the user it is too long for the server. Returns whether it was sent.
+fn send(conn: &mut Client, msg: &Message, message_history: &mut Vec<HistoryLine>) -> bool {
+    match conn.send(msg) {
+        Ok(()) => true,
+        Err(err) => {
+            message_history.push(format!("Message not sent: too long ({} bytes, server limit is {}).", err.len, err.max_len).into());
+            false
+        },
+    }
+}
+
+/// What to tell the user when the connection is lost.
+fn describe_lost_connection(err: &io::Error) -> String {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof => "Disconnected".into(),
+        io::ErrorKind::TimedOut => format!("Connection lost: {}.", err),
+        _ => match err.get_ref().and_then(|inner| inner.downcast_ref::<FrameTooLarge>()) {
+            Some(err) => format!("Disconnected: server sent a {}", err),
+            None => format!("Disconnected: {}", err),
+        },
+    }
+}
+
+/// Whether something that happened in event_room happened in our room (which is the default one
+/// if the server never said otherwise).
+fn in_room(room: &Option<String>, event_room: &str) -> bool {
+    room.as_deref().unwrap_or(DEFAULT_ROOM) == event_room
+}
+
+/// How long to wait before reconnection attempt number `attempt` (counting from 0): exponential backoff,
+/// with jitter so that clients that lost their connections together do not all come back at once.
+fn reconnect_delay(attempt: u32) -> Duration {
+    use std::hash::{BuildHasher, Hasher};
+    let backoff = MIN_RECONNECT_DELAY.checked_mul(1 << attempt.min(16)).unwrap_or(MAX_RECONNECT_DELAY).min(MAX_RECONNECT_DELAY);
+    // RandomState is randomly seeded, which is all the randomness needed here
+    let mut hasher = std::collections::hash_map::RandomState::new().build_hasher();
+    hasher.write_u32(attempt);
+    let permille = (hasher.finish() % 1000) as u32;
+    backoff / 2 + backoff / 2 * permille / 1000
+}
+
+/// Connects on a thread of its own, so that the UI keeps going however long the server takes;
+/// the result arrives on the returned channel.
+fn connect_in_background(addr: SocketAddr, tls: Option<Arc<ClientTls>>, version: u16) -> mpsc::Receiver<io::Result<Client>> {
+    let (tx, rx) = mpsc::channel();
+    std::thread::spawn(move || {
+        // fails only if the user quit in the meantime
+        let _ = tx.send(Client::connect(addr, tls.as_deref(), version));
+    });
+    rx
+}
+
+const USAGE: &str = "\
+Usage: client [OPTIONS]
+
+Options:
+    --address IP[:PORT]  Server to connect to
+    --port PORT          Port to connect to
+    --nickname NAME      Name to ask for once connected
+    --tls                Connect over TLS
+    --protocol VERSION   Highest protocol version to ask the server for (default: 1). Only ask for version 2
+                         if the server supports it; version 1 servers cannot cope with being asked
+    --config PATH        Config file to read (default: $XDG_CONFIG_HOME/chatapp/client.toml, if it exists)
+    --log-level LEVEL    off (default), error, warn, info or debug; logs go to stderr,
+                         so redirect it elsewhere (e.g. 2>client.log)
+    --help               Show this message
+
+The config file has lines of the form `key = value`, with the keys address, port, nickname, tls, protocol and log_level,
+as for the options above (the options take precedence), and:
+    tls_ca               Only trust server certificates issued by the certificate authorities in this file (PEM);
+                         implies tls
+    tls_server_name      Name the server's certificate must be for, with tls_ca (default: the server's IP address)
+    tls_known_hosts      Without tls_ca, the server's certificate is trusted the first time, and only that
+                         certificate after that; this file is where it is remembered
+                         (default: $XDG_CONFIG_HOME/chatapp/known_hosts)
+
+The address and port are asked for if neither the options nor the config file give them.
+";
+
+/// Everything that can be set by command-line options or the config file.
+struct Settings {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    nickname: Option<String>,
+    /// Highest protocol version to ask for.
+    protocol: u16,
+    /// None if not connecting over TLS.
+    tls: Option<Trust>,
+}
+
+/// Which server certificates to trust.
+enum Trust {
+    /// Ones issued by the certificate authorities in this file, for this name (or else the server's IP address).
+    Ca(PathBuf, Option<ServerName<'static>>),
+    /// Whichever one each server has the first time, as remembered in this file.
+    FirstUse(PathBuf),
+}
+
+impl Settings {
+    /// Reads the options, and the config file, and sets the log level.
+    /// Returns None if only the usage was asked for.
+    fn load() -> Result<Option<Self>, ConfigError> {
+        let args = Args::parse(std::env::args().skip(1), &["address", "port", "nickname", "protocol", "config", "log-level"], &["tls", "help"])?;
+        if args.flag("help") {
+            return Ok(None);
+        }
+        let mut config = match (args.get("config"), config_dir()) {
+            (Some(path), _) => ConfigFile::load(path.as_ref(), true)?,
+            (None, Some(dir)) => ConfigFile::load(&dir.join("client.toml"), false)?,
+            (None, None) => ConfigFile::default(),
+        };
+
+        // the terminal UI is on stdout, but logging to stderr could still mess it up
+        let log_level = args.parsed::<Level>("log-level")?.or(config.parsed("log_level")?);
+        chatapp::set_level(log_level.unwrap_or(Level::Off));
+        let address = args.parsed_with("address", parse_address)?.or(config.parsed_with("address", parse_address)?);
+        let port = args.parsed::<u16>("port")?.or(config.integer("port")?);
+        let nickname = args.get("nickname").map(String::from).or(config.string("nickname")?);
+        let protocol = args.parsed::<u16>("protocol")?.or(config.integer("protocol")?).unwrap_or(BASE_PROTOCOL_VERSION);
+        if !(BASE_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&protocol) {
+            return Err(ConfigError(format!("protocol version must be from {} to {}", BASE_PROTOCOL_VERSION, PROTOCOL_VERSION)));
+        }
+        let tls_ca = config.string("tls_ca")?;
+        let tls = args.flag("tls") || config.boolean("tls")?.unwrap_or(false) || tls_ca.is_some();
+        let server_name = config.parsed_with("tls_server_name", |name| ServerName::try_from(name.to_owned()).map_err(|e| e.to_string()))?;
+        let known_hosts = config.string("tls_known_hosts")?.map(PathBuf::from).or_else(|| Some(config_dir()?.join("known_hosts")));
+        let trust = match (tls_ca, known_hosts) {
+            _ if !tls => None,
+            (Some(ca), _) => Some(Trust::Ca(ca.into(), server_name)),
+            (None, Some(known_hosts)) => Some(Trust::FirstUse(known_hosts)),
+            (None, None) => return Err(ConfigError("tls_known_hosts must be set, since there is no home directory to keep it in".into())),
+        };
+        config.finish()?;
+        Ok(Some(Settings {
+            ip: address.map(|(ip, _)| ip),
+            port: port.or_else(|| address.and_then(|(_, port)| port)),
+            nickname,
+            protocol,
+            tls: trust,
+        }))
+    }
+}
+
+/// $XDG_CONFIG_HOME/chatapp, or ~/.config/chatapp: where client.toml and known_hosts are.
+fn config_dir() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("chatapp"))
+}
+
+fn main() -> io::Result<()> {
+    let settings = match Settings::load() {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        Err(e) => {
+            eprintln!("client: {}\nTry client --help for more information.", e);
+            std::process::exit(2);
+        },
+    };
+
+    let (ip, port) = match settings.ip {
+        Some(ip) => (ip, settings.port),
+        None => {
+            let (ip, port) = get_user_input(
+                io::stdout().lock(),
+                io::stdin().lock(),
+                "Server IP: ",
+                "Invalid IP",
+                |s| parse_address(s).ok(),
+            )?;
+            (ip, settings.port.or(port))
+        },
+    };
+    let port: u16 = match port {
+        Some(port) => port,
+        None => get_user_input(
+            io::stdout().lock(),
+            io::stdin().lock(),
+            "Server port: ",
+            "Invalid port",
+            |s| s.trim().parse().ok()
+        )?,
+    };
+    let addr: SocketAddr = (ip, port).into();
+    // shared with the threads reconnecting
+    let tls = match settings.tls {
+        Some(Trust::Ca(ca, server_name)) => Some(Arc::new(ClientTls::with_ca(&ca, server_name.unwrap_or_else(|| ip.into()))?)),
+        Some(Trust::FirstUse(known_hosts)) => Some(Arc::new(ClientTls::trust_on_first_use(known_hosts, addr))),
+        None => None,
+    };
+
+    // TUI init
+    let mut terminal = tui::Terminal::new(
+        tui::backend::TermionBackend::new(
+            termion::screen::AlternateScreen::from(
+                termion::input::MouseTerminal::from(
+                    termion::raw::IntoRawMode::into_raw_mode(io::stdout())?
+                )
+            )
+        )
+    )?;
+
+    let connection = Client::connect(addr, tls.as_deref(), settings.protocol)?;
+    let mut name = connection.name().to_owned();
+    // None while waiting to reconnect.
+    let mut connection = Some(connection);
+    // While disconnected: how many attempts to reconnect have failed, when to make the next one,
+    // and where the result of the one being made will arrive.
+    let mut reconnect_attempts: u32 = 0;
+    let mut reconnect_at = Instant::now();
+    let mut reconnecting: Option<mpsc::Receiver<io::Result<Client>>> = None;
+    let mut message_history: Vec<HistoryLine> = vec![];
+    let mut new_name: Option<String> = None;
+    // The password we asked to register our name with, and the name and password we asked to log in with,
+    // until the server answers.
+    let mut pending_registration: Option<String> = None;
+    let mut pending_login: Option<(String, String)> = None;
+    // The account we are logged in to, if any, to log in again after reconnecting.
+    let mut credentials: Option<(String, String)> = None;
+    // Only known if the server supports rooms. Kept while reconnecting, to tell whether we are back in the same room.
+    let mut room: Option<String> = None;
+    // Everyone connected, kept up to date by events if the server supports the roster.
+    let mut roster: BTreeSet<String> = BTreeSet::new();
+    // How many lines the message list is scrolled up from the bottom,
+    // and how many fit in it (as of the last draw).
+    let mut scroll: usize = 0;
+    let mut visible_lines: usize = 0;
+    // Scrollback for the current room, if the server supports history:
+    // the ids of the oldest and newest chat messages we have, whether the server has older ones,
+    // and whether we are waiting for a HistoryBatch we asked for.
+    let mut oldest_message_id: Option<u64> = None;
+    let mut newest_message_id: Option<u64> = None;
+    let mut more_history = false;
+    let mut history_requested = false;
+    if let Some(fingerprint) = tls.as_deref().and_then(ClientTls::take_newly_trusted) {
+        message_history.push(format!("Trusting the server's certificate from now on (SHA-256 fingerprint {})", fingerprint).into());
+    }
+    message_history.push(format!("Name: {}", name).into());
+    if let Some(nickname) = settings.nickname.filter(|nickname| *nickname != name) {
+        if send(connection.as_mut().unwrap(), &Message::NameChangeRequest((&nickname).into()), &mut message_history) {
+            message_history.push(format!("You requested new name: {}", nickname).into());
+            new_name = Some(nickname);
+        }
+    }
+
+    // TODO: use tui crate with a window above for message history and a text entry box for message entry
+
+    let (tx, input_rx) = mpsc::channel();
+    let _input_thread_handle = std::thread::spawn(move || -> io::Result<()> {
+        use termion::input::TermRead;
+        for event in io::stdin().keys() {
+            tx.send(event?).unwrap();
+        }
+        Ok(())
+    });
+
+    let mut input_line: String = String::new();
+    loop {
+        use Message::*;
+        use mpsc::TryRecvError;
+        if connection.is_none() && reconnecting.is_none() && Instant::now() >= reconnect_at {
+            reconnecting = Some(connect_in_background(addr, tls.clone(), settings.protocol));
+        }
+        let reconnected = match reconnecting.as_ref().map(mpsc::Receiver::try_recv) {
+            Some(Ok(result)) => Some(result),
+            Some(Err(TryRecvError::Disconnected)) => Some(Err(io::Error::other("connecting failed unexpectedly"))),
+            Some(Err(TryRecvError::Empty)) | None => None,
+        };
+        if let Some(result) = reconnected {
+            reconnecting = None;
+            match result {
+                Ok(mut conn) => {
+                    let assigned_name = conn.name().to_owned();
+                    log!(Level::Info, "Reconnected to {}", addr);
+                    if let Some(fingerprint) = tls.as_deref().and_then(ClientTls::take_newly_trusted) {
+                        message_history.push(format!("Trusting the server's certificate from now on (SHA-256 fingerprint {})", fingerprint).into());
+                    }
+                    message_history.push(format!("Reconnected as {}", assigned_name).into());
+                    reconnect_attempts = 0;
+                    if let Some((account, password)) = &credentials {
+                        // our name is registered, so nobody else can have taken it
+                        if send(&mut conn, &LoginRequest { name: account.into(), password: password.into() }, &mut message_history) {
+                            pending_login = credentials.clone();
+                        }
+                        name = assigned_name;
+                    } else if assigned_name != name {
+                        // ask for our old name back; someone may have taken it in the meantime
+                        if send(&mut conn, &NameChangeRequest((&name).into()), &mut message_history) {
+                            message_history.push(format!("You requested new name: {}", name).into());
+                            new_name = Some(std::mem::replace(&mut name, assigned_name));
+                        } else {
+                            name = assigned_name;
+                        }
+                    }
+                    connection = Some(conn);
+                },
+                Err(e) => {
+                    log!(Level::Info, "Reconnecting to {} failed: {}", addr, e);
+                    reconnect_attempts += 1;
+                    reconnect_at = Instant::now() + reconnect_delay(reconnect_attempts);
+                    message_history.push(format!("Reconnecting failed: {}", e).into());
+                },
+            };
+        }
+        // Set when the connection is over, to what to tell the user.
+        let mut lost: Option<String> = None;
+        // Lines added at the bottom while scrolled up should not move what is on screen.
+        let lines_before = message_history.len();
+        let mut lines_prepended = 0;
+        if let Some(conn) = &mut connection {
+            loop {
+                let event = match conn.poll_event() {
+                    Ok(Some(event)) => event,
+                    Ok(None) => break,
+                    Err(e) => {
+                        lost = Some(describe_lost_connection(&e));
+                        break;
+                    },
+                };
+                match event {
+                    Event::Message(Disconnect) => {
+                        lost = Some("Disconnected by the server".into());
+                        break;
+                    },
+                    Event::Message(Welcome { capabilities, .. }) => {
+                        if capabilities.contains(Capabilities::ROSTER) {
+                            send(conn, &RosterRequest, &mut message_history);
+                        }
+                    },
+                    Event::Message(Roster(names)) => {
+                        roster = names.into_iter().map(Cow::into_owned).collect();
+                    },
+                    // Without the system events capability, the server also sends these as chat messages
+                    // (but only about our room: these are about everyone, for the roster).
+                    Event::Message(UserJoined { room: event_room, name }) => {
+                        if conn.capabilities().contains(Capabilities::SYSTEM_EVENTS) && in_room(&room, &event_room) {
+                            message_history.push(HistoryLine::new(LineKind::Event, format!("{} joined", name)));
+                        }
+                        roster.insert(name.into_owned());
+                    },
+                    Event::Message(UserLeft { room: event_room, name, reason }) => {
+                        if conn.capabilities().contains(Capabilities::SYSTEM_EVENTS) && in_room(&room, &event_room) {
+                            let how = match reason {
+                                LEAVE_DISCONNECTED => "disconnected",
+                                LEAVE_CONNECTION_LOST => "lost connection",
+                                LEAVE_REMOVED => "was removed by the server",
+                                _ => "left",
+                            };
+                            message_history.push(HistoryLine::new(LineKind::Event, format!("{} {}", name, how)));
+                        }
+                        roster.remove(&*name);
+                    },
+                    Event::Message(UserRenamed { room: event_room, old_name, new_name }) => {
+                        // our own renames are already reported from NameChangeApproval
+                        if conn.capabilities().contains(Capabilities::SYSTEM_EVENTS) && in_room(&room, &event_room) && new_name != name {
+                            message_history.push(HistoryLine::new(LineKind::Event, format!("{} is now known as {}", old_name, new_name)));
+                        }
+                        roster.remove(&*old_name);
+                        roster.insert(new_name.into_owned());
+                    },
+                    Event::Message(DirectMessage { peer, text }) => {
+                        message_history.push(HistoryLine::new(LineKind::Direct, format!("[{} -> you] {}", peer, text)));
+                    },
+                    Event::Message(DirectMessageError { reason: DIRECT_NO_SUCH_USER, recipient }) => {
+                        message_history.push(format!("Direct message not delivered: there is no user named {}.", recipient).into());
+                    },
+                    Event::Message(DirectMessageError { reason: DIRECT_MUTED, recipient }) => {
+                        message_history.push(format!("Direct message to {} not delivered: you are muted.", recipient).into());
+                    },
+                    Event::Message(DirectMessageError { reason: DIRECT_RATE_LIMITED, recipient }) => {
+                        message_history.push(format!("Direct message to {} not delivered: you are sending messages too fast.", recipient).into());
+                    },
+                    Event::Message(DirectMessageError { reason, recipient }) => {
+                        message_history.push(format!("Direct message to {} not delivered: {}.", recipient, reason).into());
+                    },
+                    Event::Message(RoomJoined(new_room)) => {
+                        message_history.push(format!("You are now in {}", new_room).into());
+                        if room.as_deref() != Some(&*new_room) {
+                            // the server sends the new room's recent messages next
+                            oldest_message_id = None;
+                            newest_message_id = None;
+                            more_history = false;
+                            history_requested = false;
+                        }
+                        room = Some(new_room.into());
+                    },
+                    Event::Message(RoomJoinDenial(reason)) => {
+                        message_history.push(format!("Room join denied: {}.", reason).into());
+                    },
+                    Event::Message(RoomList(rooms)) => {
+                        let rooms: Vec<String> = rooms.iter().map(|(room, members)| format!("{} ({})", room, members)).collect();
+                        message_history.push(format!("Rooms: {}", rooms.join(", ")).into());
+                    },
+                    Event::Message(ChatMessage(s)) => {
+                        message_history.push(s.into());
+                    },
+                    Event::Message(RelayedChatMessage { sender, text }) => {
+                        message_history.push(HistoryLine::chat(sender, text));
+                    },
+                    Event::Message(StampedChatMessage { id, timestamp, sender, text }) => {
+                        oldest_message_id.get_or_insert(id);
+                        newest_message_id = Some(id);
+                        message_history.push(HistoryLine::stamped(timestamp, sender, text));
+                    },
+                    // a batch for a room we have since left is no use
+                    Event::Message(HistoryBatch { room: batch_room, .. }) if matches!(&room, Some(room) if *room != batch_room) => {},
+                    Event::Message(HistoryBatch { room: batch_room, messages, more }) => {
+                        let first_id = messages.first().map(|message| message.id);
+                        if history_requested {
+                            // older than everything we have
+                            history_requested = false;
+                            oldest_message_id = first_id.or(oldest_message_id);
+                            more_history = more;
+                            let mut lines: Vec<HistoryLine> = messages.into_iter()
+                                .map(|message| HistoryLine::stamped(message.timestamp, message.sender, message.text))
+                                .collect();
+                            if !more {
+                                lines.insert(0, format!("Start of history in {}", batch_room).into());
+                            }
+                            lines_prepended += lines.len();
+                            message_history.splice(0..0, lines);
+                        } else {
+                            // sent on joining a room, or on coming back to it after reconnecting
+                            if oldest_message_id.is_none() {
+                                oldest_message_id = first_id;
+                                more_history = more;
+                            }
+                            for message in messages {
+                                if matches!(newest_message_id, Some(newest) if message.id <= newest) {
+                                    continue;
+                                }
+                                newest_message_id = Some(message.id);
+                                message_history.push(HistoryLine::stamped(message.timestamp, message.sender, message.text));
+                            }
+                        }
+                    },
+                    Event::Message(ChatMessageError(reason)) => {
+                        message_history.push(describe_chat_error(reason).into());
+                    },
+                    Event::Message(NameChangeApproval) => {
+                        name = new_name.take().unwrap();
+                        message_history.push(format!("New name: {}", name).into());
+                    },
+                    Event::Message(NameChangeDenial(reason)) => {
+                        let denied_name = new_name.take().unwrap();
+                        message_history.push(format!("Name request ({}) denied: {}.", denied_name, describe_name_denial(reason)).into());
+                    },
+                    Event::Message(ModerationDenial(reason)) => {
+                        message_history.push(format!("Denied: {}.", describe_moderation_error(reason)).into());
+                    },
+                    // replies to requests we did not make are ignored
+                    Event::Message(RegisterApproval) => {
+                        if let Some(password) = pending_registration.take() {
+                            credentials = Some((name.clone(), password));
+                            message_history.push(format!("Registered {}; nobody else can use this name now.", name).into());
+                        }
+                    },
+                    Event::Message(RegisterDenial(reason)) => {
+                        if pending_registration.take().is_some() {
+                            message_history.push(format!("Registration denied: {}.", describe_account_denial(reason)).into());
+                        }
+                    },
+                    Event::Message(LoginApproval(account)) => {
+                        if let Some((_, password)) = pending_login.take() {
+                            name = account.into_owned();
+                            credentials = Some((name.clone(), password));
+                            message_history.push(format!("Logged in as {}", name).into());
+                        }
+                    },
+                    Event::Message(LoginDenial(reason)) => {
+                        if let Some((account, _)) = pending_login.take() {
+                            message_history.push(format!("Login as {} denied: {}.", account, describe_account_denial(reason)).into());
+                        }
+                    },
+                    Event::Message(msg) => {
+                        log!(Level::Warn, "Unexpected message from server: {:?}", msg);
+                        message_history.push(format!("Unexpected message from server (type {}).", msg.message_type()).into());
+                    },
+                    Event::Invalid(frame) => {
+                        log!(Level::Warn, "Invalid message from server: {:?}", frame);
+                        message_history.push(format!("Invalid message from server (type {:?}).", frame.first()).into());
+                    },
+                };
+            }
+        }
+        if scroll > 0 {
+            scroll += message_history.len() - lines_before - lines_prepended;
+        }
+        let capabilities = connection.as_ref().map_or(Capabilities::NONE, Client::capabilities);
+        use termion::event::Key;
+        match input_rx.try_recv() {
+            Ok(Key::Char('\n')) if input_line.starts_with("/disconnect") => {
+                message_history.push("Disconnecting".into());
+                break;
+            },
+            // keep what was typed, so it can be sent once reconnected
+            Ok(Key::Char('\n')) if connection.is_none() || lost.is_some() => {
+                message_history.push("Not connected; press Enter again once reconnected, or /disconnect to quit.".into());
+            },
+            Ok(Key::Char('\n')) => {
+                let conn = connection.as_mut().unwrap();
+                if let Some(name_request) = input_line.strip_prefix("/name ") {
+                    let name_request = name_request.trim();
+                    if send(conn, &Message::NameChangeRequest(name_request.into()), &mut message_history) {
+                        new_name = Some(name_request.into());
+                        message_history.push(format!("You requested new name: {}", name_request).into());
+                    }
+                } else if let Some(args) = input_line.strip_prefix("/msg") {
+                    let mut args = args.trim_start().splitn(2, ' ');
+                    match (args.next().filter(|recipient| !recipient.is_empty()), args.next()) {
+                        _ if !capabilities.contains(Capabilities::DIRECT_MESSAGES) => {
+                            message_history.push("This server does not support direct messages.".into());
+                        },
+                        (Some(recipient), Some(text)) if !text.trim().is_empty() => {
+                            let msg = Message::DirectMessage { peer: recipient.into(), text: text.into() };
+                            if send(conn, &msg, &mut message_history) {
+                                message_history.push(HistoryLine::new(LineKind::Direct, format!("[you -> {}] {}", recipient, text)));
+                            }
+                        },
+                        _ => message_history.push("Usage: /msg <name> <text>".into()),
+                    };
+                } else if input_line.starts_with("/join") || input_line.starts_with("/part") || input_line.starts_with("/rooms") {
+                    if !capabilities.contains(Capabilities::ROOMS) {
+                        message_history.push("This server does not support rooms.".into());
+                    } else if let Some(room_request) = input_line.strip_prefix("/join ") {
+                        let room_request = room_request.trim();
+                        send(conn, &Message::RoomJoinRequest(room_request.into()), &mut message_history);
+                    } else if input_line.trim() == "/part" {
+                        send(conn, &Message::RoomPartRequest, &mut message_history);
+                    } else if input_line.trim() == "/rooms" {
+                        send(conn, &Message::RoomListRequest, &mut message_history);
+                    } else {
+                        message_history.push("Usage: /join <room>, /part, /rooms".into());
+                    }
+                } else if input_line.starts_with("/register") || input_line.starts_with("/login") {
+                    let mut args = input_line.split_whitespace().skip(1);
+                    match (input_line.starts_with("/register"), args.next(), args.next(), args.next()) {
+                        _ if !capabilities.contains(Capabilities::ACCOUNTS) => {
+                            message_history.push("This server does not support accounts.".into());
+                        },
+                        // the server only takes one at a time
+                        _ if pending_registration.is_some() || pending_login.is_some() => {
+                            message_history.push("Wait for the answer to your last /register or /login first.".into());
+                        },
+                        (true, Some(password), None, None) => {
+                            if send(conn, &Message::RegisterRequest { password: password.into() }, &mut message_history) {
+                                pending_registration = Some(password.into());
+                            }
+                        },
+                        (false, Some(account), Some(password), None) => {
+                            if send(conn, &Message::LoginRequest { name: account.into(), password: password.into() }, &mut message_history) {
+                                pending_login = Some((account.into(), password.into()));
+                            }
+                        },
+                        _ => message_history.push("Usage: /register <password>, /login <name> <password>".into()),
+                    };
+                } else if input_line.starts_with("/kick") || input_line.starts_with("/ban") || input_line.starts_with("/mute") {
+                    let (command, args) = next_word(&input_line);
+                    let (target, args) = next_word(args);
+                    // /kick takes no duration; the others take one before the reason
+                    let (duration, reason) = match command {
+                        "/kick" => (Some(0), args),
+                        _ => {
+                            let (duration, reason) = next_word(args);
+                            (parse_duration(duration), reason)
+                        },
+                    };
+                    let action = match command {
+                        "/kick" => Some(ModerationAction::Kick),
+                        "/ban" => Some(ModerationAction::Ban),
+                        "/mute" => Some(ModerationAction::Mute),
+                        _ => None,
+                    };
+                    match (action, duration) {
+                        _ if !capabilities.contains(Capabilities::MODERATION) => {
+                            message_history.push("This server does not support moderation.".into());
+                        },
+                        (Some(action), Some(duration_secs)) if !target.is_empty() => {
+                            let reason = reason.trim().into();
+                            send(conn, &Message::ModerationRequest { action, target: target.into(), duration_secs, reason }, &mut message_history);
+                        },
+                        _ => message_history.push("Usage: /kick <name> [reason], /ban <name or IP> <duration> [reason], /mute <name> <duration> [reason] \
+                            (durations like 30s, 10m, 2h or 7d; 0 lifts a ban or mute)".into()),
+                    };
+                } else if input_line.starts_with("/") {
+                    message_history.push(format!("Command not implemented: {}", input_line).into());
+                } else if !input_line.is_empty() && send(conn, &Message::ChatMessage(input_line.as_str().into()), &mut message_history) {
+                    message_history.push(HistoryLine::chat("(you)", input_line.clone()));
+                }
+                input_line.clear();
+            },
+            Ok(Key::Backspace) => {
+                input_line.pop();
+            },
+            Ok(Key::Char(c)) => {
+                input_line.push(c);
+            },
+            Ok(key @ Key::Up) | Ok(key @ Key::PageUp) => {
+                let step = if key == Key::Up { 1 } else { visible_lines.max(1) };
+                let max_scroll = message_history.len().saturating_sub(visible_lines);
+                if scroll + step > max_scroll && capabilities.contains(Capabilities::HISTORY) && more_history && !history_requested {
+                    let before_id = oldest_message_id.unwrap_or(u64::MAX);
+                    // capabilities are only non-empty while connected
+                    let request = Message::HistoryRequest { before_id, count: HISTORY_PAGE_LEN };
+                    history_requested = send(connection.as_mut().unwrap(), &request, &mut message_history);
+                }
+                scroll = (scroll + step).min(max_scroll);
+            },
+            Ok(key @ Key::Down) | Ok(key @ Key::PageDown) => {
+                let step = if key == Key::Down { 1 } else { visible_lines.max(1) };
+                scroll = scroll.saturating_sub(step);
+            },
+            Ok(Key::Ctrl('d')) | Err(TryRecvError::Disconnected) => {
+                message_history.push("Disconnecting".into());
+                break;
+            },
+            Ok(k) => {
+                message_history.push(format!("Key not implemented: {:?}", k).into());
+            },
+            Err(TryRecvError::Empty) => {},
+        };
+
+        if let Some(reason) = lost {
+            log!(Level::Info, "{}", reason);
+            message_history.push(reason.into());
+            connection = None;
+            new_name = None;
+            pending_registration = None;
+            pending_login = None;
+            history_requested = false;
+            reconnect_at = Instant::now() + reconnect_delay(0);
+        }
+
+        terminal.draw(|f| {
+            use tui::layout::{Constraint, Direction, Layout};
+            let chunks = Layout::default()
+                .direction(Direction::Vertical)
+                .margin(2)
+                .constraints(
+                    [
+                        Constraint::Length(1),
+                        Constraint::Length(3),
+                        Constraint::Min(1),
+                    ].as_ref()
+                ).split(f.size());
+
+            use tui::text::Text;
+            use tui::widgets::{Paragraph, Block, Borders, List, ListItem};
+//            use tui::text::{Text, Spans, Span};
+//            use tui::style::{Style, Color, Modifier};
+
+            let status = match (&connection, &room) {
+                (Some(conn), Some(room)) => format!("Name: {}  Room: {} (protocol v{})", escape(&name), escape(room), conn.protocol_version()),
+                (Some(conn), None) => format!("Name: {} (protocol v{})", escape(&name), conn.protocol_version()),
+                (None, _) if reconnecting.is_some() => {
+                    format!("Name: {}  Disconnected; reconnecting (attempt {})", escape(&name), reconnect_attempts + 1)
+                },
+                (None, _) => {
+                    let wait = reconnect_at.saturating_duration_since(Instant::now());
+                    let secs = wait.as_millis().div_ceil(1000);
+                    format!("Name: {}  Disconnected; reconnecting in {}s (attempt {})", escape(&name), secs, reconnect_attempts + 1)
+                },
+            };
+            let name_box = Paragraph::new(Text::from(status));
+            f.render_widget(name_box, chunks[0]);
+
+            let input_prompt = Paragraph::new(Text::from(escape(&mask_password(&input_line)).into_owned()))
+                .block(Block::default().borders(Borders::ALL).title("Input"));
+            f.render_widget(input_prompt, chunks[1]);
+
+            let (messages_area, roster_area) = if capabilities.contains(Capabilities::ROSTER) && connection.is_some() {
+                let columns = Layout::default()
+                    .direction(Direction::Horizontal)
+                    .constraints([Constraint::Min(1), Constraint::Length(24)].as_ref())
+                    .split(chunks[2]);
+                (columns[0], Some(columns[1]))
+            } else {
+                (chunks[2], None)
+            };
+
+            let message_count = (messages_area.height - 2) as usize;
+            visible_lines = message_count;
+
+            let title = match scroll {
+                0 => "Messages".to_string(),
+                _ => format!("Messages ({} newer below)", scroll),
+            };
+            let messages: List = List::new(
+                message_history.iter().rev().skip(scroll).take(message_count).rev()
+                    .map(HistoryLine::to_list_item)
+                    .collect::<Vec<_>>()
+            ).block(Block::default().borders(Borders::ALL).title(title));
+            f.render_widget(messages, messages_area);
+
+            if let Some(roster_area) = roster_area {
+                let users: List = List::new(
+                    roster.iter()
+                        .map(|name| ListItem::new(escape(name)))
+                        .collect::<Vec<_>>()
+                ).block(Block::default().borders(Borders::ALL).title(format!("Users ({})", roster.len())));
+                f.render_widget(users, roster_area);
+            }
+        })?;
+
+        std::thread::sleep(std::time::Duration::from_millis(50));
+//        let s = format!("Hello, {:?}.", addr);
+//        socket.write(&[s.len() as u8])?;
+//        socket.write(s.as_bytes())?;
+//
+//        let mut x = [0u8];
+//        socket.read_exact(&mut x)?;
+//        let mut s = String::new();
+//        Read::take(&socket, x[0] as u64).read_to_string(&mut s)?;
+//        println!("Received \"{}\" from {:?}.", s, addr);
+    }
+
+    // Best effort; we are leaving either way.
+    if let Some(conn) = connection.take() {
+        let _ = conn.disconnect();
+    }
+
+    terminal.draw(|f| {
+        use tui::layout::{Constraint, Direction, Layout};
+        let chunks = Layout::default()
+            .direction(Direction::Vertical)
+            .margin(2)
+            .constraints(
+                [
+                    Constraint::Length(4),
+                    Constraint::Min(1),
+                ].as_ref()
+            ).split(f.size());
+
+        use tui::text::Text;
+        use tui::widgets::{Paragraph, Block, Borders, List};
+//            use tui::text::{Text, Spans, Span};
+//            use tui::style::{Style, Color, Modifier};
+
+        let disconnected_box = Paragraph::new(Text::from("Disconnected"));
+        f.render_widget(disconnected_box, chunks[0]);
+
+        let message_count = (chunks[1].height - 2) as usize;
+
+        let messages: List = List::new(
+            message_history.iter().rev().take(message_count).rev()
+                .map(HistoryLine::to_list_item)
+                .collect::<Vec<_>>()
+        ).block(Block::default().borders(Borders::ALL).title("Messages"));
+        f.render_widget(messages, chunks[1]);
+    })?;
+
+    std::thread::sleep(std::time::Duration::from_millis(1000));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_backs_off_up_to_a_limit() {
+        for attempt in 0..100 {
+            let backoff = (MIN_RECONNECT_DELAY * 2u32.pow(attempt.min(10))).min(MAX_RECONNECT_DELAY);
+            let delay = reconnect_delay(attempt);
+            assert!(delay >= backoff / 2 && delay <= backoff, "attempt {} waits {:?}", attempt, delay);
+        }
+        assert!(reconnect_delay(0) <= MIN_RECONNECT_DELAY);
+        assert!(reconnect_delay(u32::MAX) >= MAX_RECONNECT_DELAY / 2);
+    }
+}
@@ -0,0 +1,226 @@
+use std::net::*;
+use std::io;
+use std::time::Duration;
+use std::path::PathBuf;
+
+#[macro_use]
+extern crate chatapp;
+use chatapp::Level;
+use chatapp::config::*;
+use chatapp::util::*;
+use chatapp::tls::server_config;
+use chatapp::server::{Server, ServerConfig, RateLimits, NamePolicy, Limit};
+
+/// Read if it exists and no --config option is given.
+const DEFAULT_CONFIG_PATH: &str = "server.toml";
+/// Where the history log is kept.
+const DEFAULT_HISTORY_DIR: &str = "chat-history";
+/// Where registered names are kept.
+const DEFAULT_ACCOUNTS_FILE: &str = "accounts.txt";
+
+const USAGE: &str = "\
+Usage: server [OPTIONS]
+
+Options:
+    --address IP[:PORT]  Address to listen on
+    --port PORT          Port to listen on
+    --config PATH        Config file to read (default: server.toml, if it exists)
+    --log-level LEVEL    off, error, warn, info or debug (default: info)
+    --help               Show this message
+
+The config file has lines of the form `key = value`. Keys:
+    address, port, log_level   As for the options above (the options take precedence)
+    max_frame_len              Longest message clients may send, in bytes (default: 65536)
+    history                    Whether to keep a history log (default: true)
+    history_dir                Where to keep it (default: \"chat-history\")
+    accounts                   Whether clients may register names with a password (default: true)
+    accounts_file              Where to keep registered names (default: \"accounts.txt\")
+    operators                  Comma-separated accounts whose clients may kick, ban and mute others (default: none)
+    chat_per_minute            How many chat and direct messages each client may send per minute (default: 60)
+    chat_burst                 ... and how many at once (default: 10)
+    name_changes_per_minute    How many name changes, registrations and logins per minute (default: 6)
+    name_change_burst          ... and how many at once (default: 3)
+    passwords_per_minute       How many registrations and logins per minute from each IP address (default: 10)
+    password_burst             ... and how many at once (default: 5)
+    bytes_per_second           How many bytes each client may send per second (default: 32768)
+    bytes_burst                ... and how many at once (default: 262144)
+                               (a limit of 0 per minute or second means no limit; any other needs a burst of 1 or more)
+    rate_limit_strikes         How many messages in a row may be rejected for exceeding these limits before
+                               the client is disconnected (default: 20; 0 for never)
+    ping_interval_secs         How long clients may be quiet before being pinged (default: 30)
+    ping_timeout_secs          How long they then have to answer (default: 30)
+    name_max_width             Widest name clients may ask for, in terminal columns (default: 20)
+    name_allow_unicode         Whether names may have letters and digits other than ASCII ones (default: true)
+    name_allowed_punctuation   Other characters names may have (default: \"-_.'\")
+    name_allow_spaces          Whether names may have spaces between words (default: false)
+    reserved_names             Comma-separated names (or look-alikes) nobody may ask for
+                               (default: \"server, admin, administrator, moderator, operator, root, system\")
+    sanitize                   What to do with control characters, bidi overrides etc. in messages:
+                               \"escape\" them visibly (the default), \"strip\" them, or leave them (\"off\")
+    tls_cert                   Certificate chain to serve TLS with (PEM); only TLS connections are accepted if set
+    tls_key                    Its private key (PEM); must be set along with tls_cert
+
+The address and port are asked for if neither the options nor the config file give them.
+";
+
+/// Everything that can be set by command-line options or the config file.
+struct Settings {
+    ip: Option<IpAddr>,
+    port: Option<u16>,
+    /// Certificate chain and private key files, if TLS is enabled.
+    tls: Option<(PathBuf, PathBuf)>,
+    server: ServerConfig,
+}
+
+impl Settings {
+    /// Reads the options, and the config file, and sets the log level.
+    /// Returns None if only the usage was asked for.
+    fn load() -> Result<Option<Self>, ConfigError> {
+        let args = Args::parse(std::env::args().skip(1), &["address", "port", "config", "log-level"], &["help"])?;
+        if args.flag("help") {
+            return Ok(None);
+        }
+        let mut config = match args.get("config") {
+            Some(path) => ConfigFile::load(path.as_ref(), true)?,
+            None => ConfigFile::load(DEFAULT_CONFIG_PATH.as_ref(), false)?,
+        };
+
+        let log_level = args.parsed::<Level>("log-level")?.or(config.parsed("log_level")?);
+        chatapp::set_level(log_level.unwrap_or(Level::Info));
+        let address = args.parsed_with("address", parse_address)?.or(config.parsed_with("address", parse_address)?);
+        let port = args.parsed::<u16>("port")?.or(config.integer("port")?);
+        let history = config.boolean("history")?.unwrap_or(true);
+        let history_dir = config.string("history_dir")?.unwrap_or_else(|| DEFAULT_HISTORY_DIR.into());
+        let accounts = config.boolean("accounts")?.unwrap_or(true);
+        let accounts_file = config.string("accounts_file")?.unwrap_or_else(|| DEFAULT_ACCOUNTS_FILE.into());
+        let tls = match (config.string("tls_cert")?, config.string("tls_key")?) {
+            (Some(cert), Some(key)) => Some((cert.into(), key.into())),
+            (None, None) => None,
+            _ => return Err(ConfigError("tls_cert and tls_key must be set together".into())),
+        };
+        let defaults = ServerConfig::default();
+        let name_policy = NamePolicy {
+            max_width: config.integer("name_max_width")?.unwrap_or(defaults.name_policy.max_width),
+            allow_unicode: config.boolean("name_allow_unicode")?.unwrap_or(defaults.name_policy.allow_unicode),
+            allowed_punctuation: config.string("name_allowed_punctuation")?.unwrap_or(defaults.name_policy.allowed_punctuation),
+            allow_spaces: config.boolean("name_allow_spaces")?.unwrap_or(defaults.name_policy.allow_spaces),
+            reserved: config.string("reserved_names")?.map_or(defaults.name_policy.reserved, |names| comma_separated(&names)),
+        };
+        let limits = defaults.rate_limits;
+        let rate_limits = RateLimits {
+            chat: config_limit(&mut config, "chat_per_minute", "chat_burst", 60.0, limits.chat)?,
+            names: config_limit(&mut config, "name_changes_per_minute", "name_change_burst", 60.0, limits.names)?,
+            passwords: config_limit(&mut config, "passwords_per_minute", "password_burst", 60.0, limits.passwords)?,
+            bytes: config_limit(&mut config, "bytes_per_second", "bytes_burst", 1.0, limits.bytes)?,
+            max_strikes: config.integer("rate_limit_strikes")?.unwrap_or(limits.max_strikes),
+        };
+        let settings = Settings {
+            ip: address.map(|(ip, _)| ip),
+            port: port.or_else(|| address.and_then(|(_, port)| port)),
+            tls,
+            server: ServerConfig {
+                max_frame_len: config.integer("max_frame_len")?.unwrap_or(defaults.max_frame_len),
+                ping_interval: config.integer("ping_interval_secs")?.map_or(defaults.ping_interval, Duration::from_secs),
+                ping_timeout: config.integer("ping_timeout_secs")?.map_or(defaults.ping_timeout, Duration::from_secs),
+                name_policy,
+                sanitize: config.parsed("sanitize")?.unwrap_or(defaults.sanitize),
+                operators: config.string("operators")?.map_or(defaults.operators, |names| comma_separated(&names)),
+                rate_limits,
+                tls: None,
+                history_dir: if history { Some(history_dir.into()) } else { None },
+                accounts_file: if accounts { Some(accounts_file.into()) } else { None },
+            },
+        };
+        config.finish()?;
+        Ok(Some(settings))
+    }
+}
+
+/// Reads a rate limit set as a count per unit_secs seconds and a burst, falling back on default for either.
+/// A limited rate with a burst below 1 would never allow anything, so it is rejected.
+fn config_limit(config: &mut ConfigFile, count_key: &str, burst_key: &str, unit_secs: f64, default: Limit) -> Result<Limit, ConfigError> {
+    let limit = Limit {
+        per_second: config.integer(count_key)?.map_or(default.per_second, |count: u32| f64::from(count) / unit_secs),
+        burst: config.integer(burst_key)?.map_or(default.burst, |burst: u32| burst.into()),
+    };
+    if limit.per_second > 0.0 && limit.burst < 1.0 {
+        return Err(ConfigError(format!("{} must be at least 1 unless {} is 0", burst_key, count_key)));
+    }
+    Ok(limit)
+}
+
+/// Splits a config setting like "alice, bob" into names.
+fn comma_separated(names: &str) -> Vec<String> {
+    names.split(',').map(str::trim).filter(|name| !name.is_empty()).map(String::from).collect()
+}
+
+fn main() -> io::Result<()> {
+    let settings = match Settings::load() {
+        Ok(Some(settings)) => settings,
+        Ok(None) => {
+            print!("{}", USAGE);
+            return Ok(());
+        },
+        Err(e) => {
+            eprintln!("server: {}\nTry server --help for more information.", e);
+            std::process::exit(2);
+        },
+    };
+
+    let (ip, port) = match settings.ip {
+        Some(ip) => (ip, settings.port),
+        None => {
+            let (ip, port) = get_user_input(
+                io::stdout().lock(),
+                io::stdin().lock(),
+                "Server IP: ",
+                "Invalid IP.\n",
+                |s| parse_address(s).ok(),
+            )?;
+            (ip, settings.port.or(port))
+        },
+    };
+    let port: u16 = match port {
+        Some(port) => port,
+        None => get_user_input(
+            io::stdout().lock(),
+            io::stdin().lock(),
+            "Server port: ",
+            "Invalid port.\n",
+            |s| s.trim().parse().ok()
+        )?,
+    };
+
+    let mut config = settings.server;
+    if let Some((cert, key)) = &settings.tls {
+        config.tls = Some(server_config(cert, key)?);
+    }
+    let server_addr: SocketAddr = (ip, port).into();
+    let listener = TcpListener::bind(server_addr)?;
+    let security = if config.tls.is_some() { " (TLS)" } else { "" };
+    log!(Level::Info, "Listening on {}{}", listener.local_addr()?, security);
+    Server::new(listener, config)?.run()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rate_limits_need_a_burst_of_at_least_1() {
+        let default = Limit::per_minute(6, 3);
+        let mut config = ConfigFile::parse("chat_per_minute = 120\nchat_burst = 5", "test.toml").unwrap();
+        assert_eq!(config_limit(&mut config, "chat_per_minute", "chat_burst", 60.0, default), Ok(Limit::per_minute(120, 5)));
+        let mut config = ConfigFile::parse("", "test.toml").unwrap();
+        assert_eq!(config_limit(&mut config, "chat_per_minute", "chat_burst", 60.0, default), Ok(default));
+
+        let mut config = ConfigFile::parse("chat_burst = 0", "test.toml").unwrap();
+        assert_eq!(
+            config_limit(&mut config, "chat_per_minute", "chat_burst", 60.0, default).unwrap_err().0,
+            "chat_burst must be at least 1 unless chat_per_minute is 0",
+        );
+        // no limit at all
+        let mut config = ConfigFile::parse("bytes_per_second = 0\nbytes_burst = 0", "test.toml").unwrap();
+        assert_eq!(config_limit(&mut config, "bytes_per_second", "bytes_burst", 1.0, default), Ok(Limit::per_second(0, 0)));
+    }
+}
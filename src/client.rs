@@ -1,241 +1,405 @@
-// Parts of this file adapted from https://github.com/fdehau/tui-rs/blob/v0.16.0/examples/user_input.rs
-// licensed by fdehau on GitHub and other tui-rs contributors under the MIT license
+//! A connection to a chat server, for bots and other programs that talk to one.
 
-use std::net::*;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
 use std::io;
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::time::{Duration, Instant};
+use libc::{POLLIN, POLLOUT};
 
-mod util;
+use crate::messages::*;
+use crate::tls::{ClientTls, Stream};
 use crate::util::*;
 
-mod messages;
-use crate::messages::*;
+/// Longest frame accepted from the server.
+/// This is more than any server accepts from clients, since relayed messages also carry names etc.
+pub const MAX_RECEIVED_FRAME_LEN: u32 = 1024 * 1024;
+/// How long the server may be quiet before it is pinged, if it supports keepalives.
+pub const PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long the server has to answer a ping before the connection is considered lost.
+pub const PING_TIMEOUT: Duration = Duration::from_secs(15);
+/// How long to wait for the server at each step of connecting.
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
 
-fn main() -> io::Result<()> {
-    let ip_and_maybe_port: (IpAddr, Option<u16>) = get_user_input(
-        io::stdout().lock(),
-        io::stdin().lock(),
-        "Server IP: ",
-        "Invalid IP",
-        |s| -> Option<(IpAddr, Option<u16>)> {
-            let s = s.trim();
-            match s.parse::<SocketAddr>() {
-                Ok(socket) => Some((socket.ip(), Some(socket.port()))),
-                Err(_) => match s.parse() {
-                    Ok(ip) => Some((ip, None)),
-                    Err(_) => None,
-                },
-            }
+/// Something the server sent.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Event {
+    /// Any message but Ping and Pong, which the client answers and consumes itself.
+    Message(Message<'static>),
+    /// A frame that could not be parsed. The connection carries on after it.
+    Invalid(Vec<u8>),
+}
+
+/// A connection to the server, and what has been negotiated over it.
+///
+/// Nothing waits on the network after connect (unless wait_event or disconnect is asked to):
+/// messages are queued and written as the socket takes them, and events are read as they arrive.
+/// Pings are answered, and if the server supports keepalives, it is pinged when it has been quiet for a while.
+pub struct Client {
+    stream: Stream,
+    decoder: FrameDecoder,
+    encoder: FrameEncoder,
+    name: String,
+    /// Names we asked for that the server has not answered yet, oldest first.
+    requested_names: VecDeque<String>,
+    /// Until the server's Welcome arrives, assume it only speaks the base protocol.
+    protocol_version: u16,
+    /// Version 1 servers do not say what their limit is.
+    max_frame_len: Option<u32>,
+    capabilities: Capabilities,
+    /// When the server last sent anything, and when we pinged it if it has not answered yet.
+    last_heard: Instant,
+    ping_sent: Option<Instant>,
+    /// An error from reading or writing, reported once the events that arrived before it have been.
+    error: Option<io::Error>,
+    /// The kind of error that ended the connection, once it has been reported.
+    ended: Option<io::ErrorKind>,
+}
+
+impl Client {
+    /// Connects, and if version is above BASE_PROTOCOL_VERSION, says hello asking for it (or PROTOCOL_VERSION,
+    /// if lower). With tls, the handshake happens along with the hello.
+    /// This waits for the server for at most a few CONNECT_TIMEOUTs.
+    ///
+    /// Version 1 servers cannot cope with a hello (they disconnect, or crash), so only ask for a later version
+    /// if the server is known to support one.
+    ///
+    /// The server's Welcome, if it sends one, is the first event; until then, it is assumed to speak
+    /// protocol version 1 without any capabilities.
+    pub fn connect(addr: SocketAddr, tls: Option<&ClientTls>, version: u16) -> io::Result<Self> {
+        let sock = TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT)?;
+        sock.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        sock.set_write_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut stream = match tls {
+            Some(tls) => tls.connect(sock)?,
+            None => Stream::Plain(sock),
+        };
+        // Servers always send a NameAssignment first (for v1 clients), and answer a hello with a Welcome.
+        if version > BASE_PROTOCOL_VERSION {
+            let hello = Message::Hello { version: version.min(PROTOCOL_VERSION), capabilities: Capabilities::SUPPORTED };
+            send_msg(&mut stream, &hello.to_bytes())?;
         }
-    )?;
-    let ip = ip_and_maybe_port.0;
-    let port: u16 = match ip_and_maybe_port.1 {
-        Some(port) => port,
-        None => get_user_input(
-            io::stdout().lock(),
-            io::stdin().lock(),
-            "Server port: ",
-            "Invalid port",
-            |s| s.trim().parse().ok()
-        )?,
-    };
-
-    // TUI init
-    let mut terminal = tui::Terminal::new(
-        tui::backend::TermionBackend::new(
-            termion::screen::AlternateScreen::from(
-                termion::input::MouseTerminal::from(
-                    termion::raw::IntoRawMode::into_raw_mode(io::stdout())?
-                )
-            )
-        )
-    )?;
-
-    let addr: SocketAddr = (ip, port).into();
-    let mut stream = TcpStream::connect(addr)?;
-    let mut name: String =
-        // get first message, which should be a NameAssignment
-        match Message::from_bytes(&recv_msg(&mut stream)?) {
-            Some(Message::NameAssignment(name)) => name.into(),
-            _ => {
-                eprintln!("Server did not respond as expected.");
-                Err(io::Error::new(io::ErrorKind::InvalidData, "Server did not send a NameAssignment message"))?;
-                unreachable!("above Err should have caused an early return from main")
-            }
+        let name = match Message::from_bytes(&recv_msg(&mut stream, MAX_RECEIVED_FRAME_LEN)?) {
+            Some(Message::NameAssignment(name)) => name.into_owned(),
+            _ => return Err(io::Error::new(io::ErrorKind::InvalidData, "Server did not send a NameAssignment message")),
         };
-    let mut message_history: Vec<std::borrow::Cow<'static, str>> = vec![];
-    let mut new_name: Option<String> = None;
-    message_history.push(format!("Name: {}", name).into());
+        stream.tcp().set_nonblocking(true)?;
+        Ok(Client {
+            stream,
+            decoder: FrameDecoder::new(MAX_RECEIVED_FRAME_LEN),
+            encoder: FrameEncoder::new(),
+            name,
+            requested_names: VecDeque::new(),
+            protocol_version: BASE_PROTOCOL_VERSION,
+            max_frame_len: None,
+            capabilities: Capabilities::NONE,
+            last_heard: Instant::now(),
+            ping_sent: None,
+            error: None,
+            ended: None,
+        })
+    }
 
-    // TODO: use tui crate with a window above for message history and a text entry box for message entry
+    /// Our name, as of the last event: the one the server assigned, until a name change or login is approved.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn protocol_version(&self) -> u16 {
+        self.protocol_version
+    }
 
-    let (tx, input_rx) = std::sync::mpsc::channel();
-    let _input_thread_handle = std::thread::spawn(move || -> io::Result<()> {
-        use termion::input::TermRead;
-        for event in io::stdin().keys() {
-            tx.send(event?).unwrap();
+    /// What the server and this client both support (none until the Welcome).
+    pub fn capabilities(&self) -> Capabilities {
+        self.capabilities
+    }
+
+    /// The longest message the server accepts, if it said.
+    pub fn max_frame_len(&self) -> Option<u32> {
+        self.max_frame_len
+    }
+
+    /// Queues msg and writes as much as the socket takes.
+    /// Messages longer than the server accepts are refused here rather than getting us disconnected.
+    /// Errors writing are returned from poll_event, after the events that arrived before them.
+    pub fn send(&mut self, msg: &Message) -> Result<(), FrameTooLarge> {
+        let bytes = msg.to_bytes();
+        match self.max_frame_len {
+            Some(max_len) if bytes.len() > max_len as usize => {
+                let len = u32::try_from(bytes.len()).unwrap_or(u32::MAX);
+                return Err(FrameTooLarge { len, max_len, message_type: msg.message_type() });
+            },
+            _ => {},
+        };
+        if let Message::NameChangeRequest(name) = msg {
+            self.requested_names.push_back(name.to_string());
         }
+        self.encoder.push(&bytes);
+        self.write();
         Ok(())
-    });
+    }
+
+    /// Sends a chat message to whoever is in the room.
+    pub fn send_chat(&mut self, text: &str) -> Result<(), FrameTooLarge> {
+        self.send(&Message::ChatMessage(text.into()))
+    }
 
+    /// Asks for a new name. The server answers with a NameChangeApproval or NameChangeDenial event.
+    pub fn change_name(&mut self, name: &str) -> Result<(), FrameTooLarge> {
+        self.send(&Message::NameChangeRequest(name.into()))
+    }
 
-    let (tx, net_rx) = std::sync::mpsc::channel::<Message<'static>>();
-    let stream_ = stream.try_clone()?;
-    let _stream_thread_handle = std::thread::spawn(move || -> io::Result<()> {
-        let mut stream = stream_;
+    /// Returns the next event if one has arrived, without waiting.
+    /// Once an error is returned, the connection is over, and so is every later call.
+    /// A server closing the connection is an error of kind UnexpectedEof,
+    /// and a server that stopped answering pings one of kind TimedOut.
+    pub fn poll_event(&mut self) -> io::Result<Option<Event>> {
+        if let Some(kind) = self.ended {
+            return Err(io::Error::new(kind, "the connection is over"));
+        }
+        self.write();
+        if self.error.is_none() {
+            self.error = self.decoder.read_from(&mut self.stream).err();
+        }
         loop {
-            let msg = recv_msg(&mut stream)?;
-            match Message::from_bytes(&msg[..]) {
-                Some(msg) => tx.send(msg.into_owned()).unwrap(),
-                None => todo!(),
+            match self.decoder.next_message() {
+                Ok(Some(msg)) => {
+                    self.last_heard = Instant::now();
+                    self.ping_sent = None;
+                    match msg {
+                        Message::Ping(value) => {
+                            self.encoder.push_message(&Message::Pong(value));
+                            self.write();
+                        },
+                        // hearing from the server at all is what matters
+                        Message::Pong(_) => {},
+                        msg => {
+                            self.update(&msg);
+                            return Ok(Some(Event::Message(msg)));
+                        },
+                    };
+                },
+                Ok(None) => break,
+                Err(DecodeError::Invalid(frame)) => return Ok(Some(Event::Invalid(frame))),
+                // nothing after this can be decoded
+                Err(DecodeError::TooLarge(err)) => {
+                    self.error = Some(err.into());
+                    break;
+                },
             };
         }
-    });
-
-    let mut input_line: String = String::new();
-    loop {
-        use Message::*;
-        use std::sync::mpsc::TryRecvError;
-        match net_rx.try_recv() {
-            Ok(Disconnect) => {
-                message_history.push("Disconnected".into());
-                break;
-            },
-            Ok(ChatMessage(s)) => {
-                message_history.push(s.into());
-            },
-            Ok(NameChangeApproval) => {
-                name = new_name.take().unwrap();
-                message_history.push(format!("New name: {}", name).into());
+        if self.error.is_none() {
+            self.keep_alive();
+        }
+        match self.error.take() {
+            Some(err) => {
+                self.ended = Some(err.kind());
+                Err(err)
             },
-            Ok(NameChangeDenial(reason)) => {
-                let denied_name = new_name.take().unwrap();
-                message_history.push(format!("Name request ({}) denied: {}.", denied_name, reason).into());
+            None => Ok(None),
+        }
+    }
+
+    /// Like poll_event, but waits up to timeout (or forever, if None) for an event to arrive.
+    pub fn wait_event(&mut self, timeout: Option<Duration>) -> io::Result<Option<Event>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        loop {
+            if let Some(event) = self.poll_event()? {
+                return Ok(Some(event));
+            }
+            let now = Instant::now();
+            if matches!(deadline, Some(deadline) if now >= deadline) {
+                return Ok(None);
+            }
+            // the keepalive needs checking on time too
+            let wake_at = match (deadline, self.keep_alive_deadline()) {
+                (Some(deadline), Some(keep_alive)) => Some(deadline.min(keep_alive)),
+                (deadline, keep_alive) => deadline.or(keep_alive),
+            };
+            let timeout = wake_at.map_or(-1, |wake_at| {
+                let wait = wake_at.saturating_duration_since(now);
+                i32::try_from(wait.as_nanos().div_ceil(1_000_000)).unwrap_or(i32::MAX)
+            });
+            // decrypted input is no longer in the socket, so poll would not say it is there
+            if self.stream.has_buffered_input() {
+                continue;
+            }
+            let events = if self.has_output() { POLLIN | POLLOUT } else { POLLIN };
+            poll_events(std::iter::once(((), self.stream.as_raw_fd(), events)), timeout)?;
+        }
+    }
+
+    /// Iterates over events as they arrive, waiting for each.
+    /// Ends after the server's Disconnect, or when it closes the connection.
+    pub fn events(&mut self) -> Events<'_> {
+        Events { client: self, done: false }
+    }
+
+    /// Says goodbye to the server, waiting (a little) for that to be written, and closes the connection.
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.encoder.push_message(&Message::Disconnect);
+        self.stream.tcp().set_nonblocking(false)?;
+        let result = self.encoder.write_to(&mut self.stream);
+        self.stream.close();
+        result
+    }
+
+    fn has_output(&self) -> bool {
+        !self.encoder.is_empty() || self.stream.wants_write()
+    }
+
+    /// Writes as much queued output as the socket takes, keeping any error for poll_event.
+    fn write(&mut self) {
+        if self.error.is_none() && self.ended.is_none() {
+            self.error = self.encoder.write_to(&mut self.stream).err();
+        }
+    }
+
+    /// Keeps track of what the server tells us about the connection and ourselves.
+    fn update(&mut self, msg: &Message) {
+        match msg {
+            Message::Welcome { version, capabilities, max_frame_len } => {
+                self.protocol_version = *version;
+                self.capabilities = *capabilities;
+                self.max_frame_len = Some(*max_frame_len);
             },
-            Err(TryRecvError::Empty) => {},
-            _ => todo!(),
-        };
-        use termion::event::Key;
-        match input_rx.try_recv() {
-            Ok(Key::Char('\n')) => {
-                if let Some(name_request) = input_line.strip_prefix("/name ") {
-                    let name_request = name_request.trim();
-                    new_name = Some(name_request.into());
-                    let msg = Message::NameChangeRequest(name_request.into());
-                    let msg_bytes = msg.to_bytes();
-                    send_msg(&mut stream, &msg_bytes)?;
-                    message_history.push(format!("You requested new name: {}", name_request).into());
-                } else if input_line.starts_with("/disconnect") {
-                    let msg = Message::Disconnect;
-                    let msg_bytes = msg.to_bytes();
-                    send_msg(&mut stream, &msg_bytes)?;
-                    message_history.push("Disconnecting".into());
-                    break;
-                } else if input_line.starts_with("/") {
-                    message_history.push(format!("Command not implemented: {}", input_line).into());
-                } else if input_line.len() > 0 {
-                    let msg = Message::ChatMessage(input_line.as_str().into());
-                    let msg_bytes = msg.to_bytes();
-                    send_msg(&mut stream, &msg_bytes)?;
-                    message_history.push(format!("(you): {}", input_line).into());
+            Message::NameChangeApproval => {
+                if let Some(name) = self.requested_names.pop_front() {
+                    self.name = name;
                 }
-                input_line.clear();
             },
-            Ok(Key::Backspace) => {
-                input_line.pop();
+            Message::NameChangeDenial(_) => {
+                self.requested_names.pop_front();
             },
-            Ok(Key::Char(c)) => {
-                input_line.push(c);
+            Message::LoginApproval(name) => {
+                self.name = name.to_string();
             },
-            Ok(Key::Ctrl('d')) | Err(TryRecvError::Disconnected) => {
-                let msg = Message::Disconnect;
-                let msg_bytes = msg.to_bytes();
-                send_msg(&mut stream, &msg_bytes)?;
-                message_history.push("Disconnecting".into());
-                break;
+            _ => {},
+        };
+    }
+
+    /// When the server should be pinged, or the connection given up on, if it supports keepalives.
+    fn keep_alive_deadline(&self) -> Option<Instant> {
+        if !self.capabilities.contains(Capabilities::KEEPALIVE) {
+            return None;
+        }
+        Some(match self.ping_sent {
+            Some(sent) => sent + PING_TIMEOUT,
+            None => self.last_heard + PING_INTERVAL,
+        })
+    }
+
+    fn keep_alive(&mut self) {
+        match self.keep_alive_deadline() {
+            Some(deadline) if Instant::now() >= deadline => {},
+            _ => return,
+        };
+        if self.ping_sent.is_some() {
+            self.error = Some(io::Error::new(io::ErrorKind::TimedOut, "the server stopped responding"));
+        } else {
+            self.encoder.push_message(&Message::Ping(unix_millis()));
+            self.ping_sent = Some(Instant::now());
+            self.write();
+        }
+    }
+}
+
+/// Iterator returned by Client::events.
+pub struct Events<'a> {
+    client: &'a mut Client,
+    done: bool,
+}
+
+impl Iterator for Events<'_> {
+    type Item = io::Result<Event>;
+
+    fn next(&mut self) -> Option<io::Result<Event>> {
+        if self.done {
+            return None;
+        }
+        match self.client.wait_event(None) {
+            Ok(Some(event)) => {
+                self.done = event == Event::Message(Message::Disconnect);
+                Some(Ok(event))
             },
-            Ok(k) => {
-                message_history.push(format!("Key not implemented: {:?}", k).into());
+            Ok(None) => None,
+            Err(e) => {
+                self.done = true;
+                match e.kind() {
+                    io::ErrorKind::UnexpectedEof => None,
+                    _ => Some(Err(e)),
+                }
             },
-            Err(TryRecvError::Empty) => {},
-        };
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::TcpListener;
 
-        terminal.draw(|f| {
-            use tui::layout::{Constraint, Direction, Layout};
-            let chunks = Layout::default()
-                .direction(Direction::Vertical)
-                .margin(2)
-                .constraints(
-                    [
-                        Constraint::Length(1),
-                        Constraint::Length(3),
-                        Constraint::Min(1),
-                    ].as_ref()
-                ).split(f.size());
-
-            use tui::text::Text;
-            use tui::widgets::{Paragraph, Block, Borders, List, ListItem};
-//            use tui::text::{Text, Spans, Span};
-//            use tui::style::{Style, Color, Modifier};
-
-            let name_box = Paragraph::new(Text::from(format!("Name: {}", name)));
-            f.render_widget(name_box, chunks[0]);
-
-            let input_prompt = Paragraph::new(Text::from(&*input_line))
-                .block(Block::default().borders(Borders::ALL).title("Input"));
-            f.render_widget(input_prompt, chunks[1]);
-
-            let message_count = (chunks[2].height - 2) as usize;
-
-            let messages: List = List::new(
-                message_history.iter().rev().take(message_count).rev()
-                    .map(|s| ListItem::new(&**s))
-                    .collect::<Vec<_>>()
-            ).block(Block::default().borders(Borders::ALL).title("Messages"));
-            f.render_widget(messages, chunks[2]);
-        })?;
-
-        std::thread::sleep(std::time::Duration::from_millis(50));
-//        let s = format!("Hello, {:?}.", addr);
-//        socket.write(&[s.len() as u8])?;
-//        socket.write(s.as_bytes())?;
-//
-//        let mut x = [0u8];
-//        socket.read_exact(&mut x)?;
-//        let mut s = String::new();
-//        Read::take(&socket, x[0] as u64).read_to_string(&mut s)?;
-//        println!("Received \"{}\" from {:?}.", s, addr);
-    }
-
-    terminal.draw(|f| {
-        use tui::layout::{Constraint, Direction, Layout};
-        let chunks = Layout::default()
-            .direction(Direction::Vertical)
-            .margin(2)
-            .constraints(
-                [
-                    Constraint::Length(4),
-                    Constraint::Min(1),
-                ].as_ref()
-            ).split(f.size());
-
-        use tui::text::Text;
-        use tui::widgets::{Paragraph, Block, Borders, List, ListItem};
-//            use tui::text::{Text, Spans, Span};
-//            use tui::style::{Style, Color, Modifier};
-
-        let disconnected_box = Paragraph::new(Text::from("Disconnected"));
-        f.render_widget(disconnected_box, chunks[0]);
-
-        let message_count = (chunks[1].height - 2) as usize;
-
-        let messages: List = List::new(
-            message_history.iter().rev().take(message_count).rev()
-                .map(|s| ListItem::new(&**s))
-                .collect::<Vec<_>>()
-        ).block(Block::default().borders(Borders::ALL).title("Messages"));
-        f.render_widget(messages, chunks[1]);
-    })?;
-
-    std::thread::sleep(std::time::Duration::from_millis(1000));
-    Ok(())
+    fn recv(stream: &mut TcpStream) -> Message<'static> {
+        Message::from_bytes(&recv_msg(stream, MAX_RECEIVED_FRAME_LEN).unwrap()).unwrap().into_owned()
+    }
+
+    #[test]
+    fn client_handles_pings_and_names() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send_msg(&mut stream, &Message::NameAssignment("user1".into()).to_bytes()).unwrap();
+            assert!(matches!(recv(&mut stream), Message::Hello { .. }));
+            let welcome = Message::Welcome { version: PROTOCOL_VERSION, capabilities: Capabilities::KEEPALIVE, max_frame_len: 64 };
+            for msg in &[welcome, Message::Ping(7), Message::RelayedChatMessage { sender: "user2".into(), text: "hi".into() }] {
+                send_msg(&mut stream, &msg.to_bytes()).unwrap();
+            }
+            assert_eq!(recv(&mut stream), Message::NameChangeRequest("bot".into()));
+            assert_eq!(recv(&mut stream), Message::Pong(7));
+            assert_eq!(recv(&mut stream), Message::ChatMessage("hello".into()));
+            send_msg(&mut stream, &Message::NameChangeApproval.to_bytes()).unwrap();
+            send_msg(&mut stream, &Message::Disconnect.to_bytes()).unwrap();
+            assert_eq!(recv(&mut stream), Message::Disconnect);
+        });
+
+        let mut client = Client::connect(addr, None, PROTOCOL_VERSION).unwrap();
+        assert_eq!(client.name(), "user1");
+        client.change_name("bot").unwrap();
+        let mut events = vec![];
+        for event in client.events() {
+            events.push(event.unwrap());
+            if let [_, Event::Message(Message::RelayedChatMessage { .. })] = &events[..] {
+                break;
+            }
+        }
+        assert_eq!(client.capabilities(), Capabilities::KEEPALIVE);
+        assert_eq!(client.max_frame_len(), Some(64));
+        assert!(client.send_chat(&"x".repeat(64)).is_err());
+        client.send_chat("hello").unwrap();
+        let events: Vec<Event> = client.events().map(Result::unwrap).collect();
+        assert_eq!(events, [Event::Message(Message::NameChangeApproval), Event::Message(Message::Disconnect)]);
+        assert_eq!(client.name(), "bot");
+        client.disconnect().unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn client_only_says_hello_when_asked_to() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            send_msg(&mut stream, &Message::NameAssignment("user1".into()).to_bytes()).unwrap();
+            assert_eq!(recv(&mut stream), Message::ChatMessage("hello".into()));
+            assert_eq!(recv(&mut stream), Message::Disconnect);
+        });
+
+        let mut client = Client::connect(addr, None, BASE_PROTOCOL_VERSION).unwrap();
+        client.send_chat("hello").unwrap();
+        assert_eq!(client.protocol_version(), BASE_PROTOCOL_VERSION);
+        client.disconnect().unwrap();
+        server.join().unwrap();
+    }
 }
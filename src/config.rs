@@ -0,0 +1,327 @@
+//! Reading settings from the command line and config files, for the client and server programs.
+
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::net::{IpAddr, SocketAddr};
+use std::path::Path;
+use std::str::FromStr;
+
+/// A problem with the command line or a config file, worded to be shown to the user as is.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConfigError(pub String);
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Parses "IP" or "IP:PORT", as accepted by the address option, config key and prompt.
+pub fn parse_address(s: &str) -> Result<(IpAddr, Option<u16>), String> {
+    let s = s.trim();
+    match s.parse::<SocketAddr>() {
+        Ok(socket) => Ok((socket.ip(), Some(socket.port()))),
+        Err(_) => match s.parse() {
+            Ok(ip) => Ok((ip, None)),
+            Err(_) => Err(format!("invalid address {:?}", s)),
+        },
+    }
+}
+
+/// Command-line options, each either `--name value`, `--name=value` or (for flags) just `--name`.
+#[derive(Debug, Default)]
+pub struct Args {
+    values: HashMap<&'static str, String>,
+    flags: Vec<&'static str>,
+}
+
+impl Args {
+    /// Parses args (not including the program name), accepting only the given options and flags.
+    pub fn parse(args: impl IntoIterator<Item=String>, options: &[&'static str], flags: &[&'static str]) -> Result<Self, ConfigError> {
+        let mut parsed = Args::default();
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let (name, value) = match arg.strip_prefix("--") {
+                Some(option) => match option.split_once('=') {
+                    Some((name, value)) => (name.to_owned(), Some(value.to_owned())),
+                    None => (option.to_owned(), None),
+                },
+                None => return Err(ConfigError(format!("unexpected argument {:?}", arg))),
+            };
+            if let Some(&flag) = flags.iter().find(|&&flag| flag == name) {
+                if value.is_some() {
+                    return Err(ConfigError(format!("--{} does not take a value", flag)));
+                }
+                parsed.flags.push(flag);
+            } else if let Some(&option) = options.iter().find(|&&option| option == name) {
+                let value = match value.or_else(|| args.next()) {
+                    Some(value) => value,
+                    None => return Err(ConfigError(format!("--{} needs a value", option))),
+                };
+                parsed.values.insert(option, value);
+            } else {
+                return Err(ConfigError(format!("unknown option --{}", name)));
+            }
+        }
+        Ok(parsed)
+    }
+
+    pub fn flag(&self, name: &str) -> bool {
+        self.flags.contains(&name)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&str> {
+        self.values.get(name).map(String::as_str)
+    }
+
+    /// The value of an option, run through parse.
+    pub fn parsed_with<T>(&self, name: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<Option<T>, ConfigError> {
+        self.get(name)
+            .map(|value| parse(value).map_err(|e| ConfigError(format!("--{}: {}", name, e))))
+            .transpose()
+    }
+
+    pub fn parsed<T: FromStr>(&self, name: &str) -> Result<Option<T>, ConfigError> where T::Err: std::fmt::Display {
+        self.parsed_with(name, |value| value.parse().map_err(|e: T::Err| e.to_string()))
+    }
+}
+
+/// A value in a config file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    String(String),
+    Integer(i64),
+    Boolean(bool),
+}
+
+/// Settings from a config file: `key = value` lines, in the subset of TOML without tables or arrays.
+/// Values are strings in double quotes, integers, or true/false. `#` starts a comment.
+///
+/// Settings are taken out one at a time, so that finish can complain about any that are left (i.e. unknown).
+#[derive(Debug, Default)]
+pub struct ConfigFile {
+    /// Where the settings came from, for error messages.
+    path: String,
+    /// Each value, and the line it was on.
+    values: HashMap<String, (usize, Value)>,
+}
+
+impl ConfigFile {
+    /// Reads and parses the config file at path. If it does not exist, that is an error
+    /// only if it is required; otherwise there are just no settings.
+    pub fn load(path: &Path, required: bool) -> Result<Self, ConfigError> {
+        match std::fs::read_to_string(path) {
+            Ok(text) => Self::parse(&text, &path.display().to_string()),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound && !required => Ok(ConfigFile::default()),
+            Err(e) => Err(ConfigError(format!("{}: {}", path.display(), e))),
+        }
+    }
+
+    pub fn parse(text: &str, path: &str) -> Result<Self, ConfigError> {
+        let mut config = ConfigFile { path: path.into(), values: HashMap::new() };
+        for (i, line) in text.lines().enumerate() {
+            let line_number = i + 1;
+            let error = |message: &str| ConfigError(format!("{}:{}: {}", path, line_number, message));
+            let line = strip_comment(line).trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None if line.starts_with('[') => return Err(error("tables are not supported")),
+                None => return Err(error("expected key = value")),
+            };
+            if key.is_empty() || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-') {
+                return Err(error(&format!("invalid key {:?}", key)));
+            }
+            let value = parse_value(value).map_err(|e| error(&e))?;
+            if config.values.insert(key.into(), (line_number, value)).is_some() {
+                return Err(error(&format!("{} is set more than once", key)));
+            }
+        }
+        Ok(config)
+    }
+
+    fn error(&self, key: &str, line_number: usize, message: &str) -> ConfigError {
+        ConfigError(format!("{}:{}: {}: {}", self.path, line_number, key, message))
+    }
+
+    pub fn string(&mut self, key: &str) -> Result<Option<String>, ConfigError> {
+        match self.values.remove(key) {
+            Some((_, Value::String(s))) => Ok(Some(s)),
+            Some((line_number, _)) => Err(self.error(key, line_number, "expected a string")),
+            None => Ok(None),
+        }
+    }
+
+    /// An integer setting, which must fit in T.
+    pub fn integer<T: TryFrom<i64>>(&mut self, key: &str) -> Result<Option<T>, ConfigError> {
+        match self.values.remove(key) {
+            Some((line_number, Value::Integer(n))) => match T::try_from(n) {
+                Ok(n) => Ok(Some(n)),
+                Err(_) => Err(self.error(key, line_number, "out of range")),
+            },
+            Some((line_number, _)) => Err(self.error(key, line_number, "expected an integer")),
+            None => Ok(None),
+        }
+    }
+
+    pub fn boolean(&mut self, key: &str) -> Result<Option<bool>, ConfigError> {
+        match self.values.remove(key) {
+            Some((_, Value::Boolean(b))) => Ok(Some(b)),
+            Some((line_number, _)) => Err(self.error(key, line_number, "expected true or false")),
+            None => Ok(None),
+        }
+    }
+
+    /// A string setting, run through parse.
+    pub fn parsed_with<T>(&mut self, key: &str, parse: impl FnOnce(&str) -> Result<T, String>) -> Result<Option<T>, ConfigError> {
+        let line_number = match self.values.get(key) {
+            Some(&(line_number, _)) => line_number,
+            None => return Ok(None),
+        };
+        match self.string(key)? {
+            Some(s) => parse(&s).map(Some).map_err(|e| self.error(key, line_number, &e)),
+            None => Ok(None),
+        }
+    }
+
+    pub fn parsed<T: FromStr>(&mut self, key: &str) -> Result<Option<T>, ConfigError> where T::Err: std::fmt::Display {
+        self.parsed_with(key, |value| value.parse().map_err(|e: T::Err| e.to_string()))
+    }
+
+    /// Complains about the first setting (by line) that was not taken.
+    pub fn finish(self) -> Result<(), ConfigError> {
+        match self.values.iter().min_by_key(|(_, (line_number, _))| *line_number) {
+            Some((key, (line_number, _))) => Err(self.error(key, *line_number, "unknown setting")),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Cuts off a `#` comment, unless the `#` is in a string.
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            '#' if !in_string => return &line[..i],
+            _ => {},
+        }
+    }
+    line
+}
+
+fn parse_value(value: &str) -> Result<Value, String> {
+    if let Some(quoted) = value.strip_prefix('"') {
+        let mut s = String::new();
+        let mut chars = quoted.chars();
+        loop {
+            match chars.next() {
+                Some('"') => break,
+                Some('\\') => s.push(match chars.next() {
+                    Some('"') => '"',
+                    Some('\\') => '\\',
+                    Some('n') => '\n',
+                    Some('t') => '\t',
+                    other => return Err(format!("unsupported escape sequence \\{}", other.map(String::from).unwrap_or_default())),
+                }),
+                Some(c) => s.push(c),
+                None => return Err("unterminated string".into()),
+            }
+        }
+        if !chars.as_str().trim().is_empty() {
+            return Err("unexpected text after string".into());
+        }
+        return Ok(Value::String(s));
+    }
+    match value {
+        "true" => return Ok(Value::Boolean(true)),
+        "false" => return Ok(Value::Boolean(false)),
+        _ => {},
+    };
+    match value.replace('_', "").parse() {
+        Ok(n) => Ok(Value::Integer(n)),
+        Err(_) => Err(format!("invalid value {:?} (strings must be in double quotes)", value)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(args: &[&str]) -> Result<Args, ConfigError> {
+        Args::parse(args.iter().map(|&arg| arg.to_owned()), &["port", "address"], &["tls", "help"])
+    }
+
+    #[test]
+    fn options_and_flags_are_parsed() {
+        let parsed = args(&["--port", "7878", "--tls", "--address=127.0.0.1"]).unwrap();
+        assert_eq!(parsed.get("port"), Some("7878"));
+        assert_eq!(parsed.parsed::<u16>("port"), Ok(Some(7878)));
+        assert_eq!(parsed.get("address"), Some("127.0.0.1"));
+        assert!(parsed.flag("tls"));
+        assert!(!parsed.flag("help"));
+        assert_eq!(parsed.parsed_with("address", parse_address), Ok(Some(("127.0.0.1".parse().unwrap(), None))));
+
+        let error = |list: &[&str]| args(list).unwrap_err().0;
+        assert_eq!(error(&["--tls=yes"]), "--tls does not take a value");
+        assert_eq!(error(&["--port"]), "--port needs a value");
+        assert_eq!(error(&["--verbose"]), "unknown option --verbose");
+        assert_eq!(error(&["7878"]), "unexpected argument \"7878\"");
+        assert!(args(&["--port", "lots"]).unwrap().parsed::<u16>("port").unwrap_err().0.starts_with("--port: "));
+    }
+
+    #[test]
+    fn values_are_parsed_and_comments_stripped() {
+        let text = "\
+# a comment on its own line
+name = \"a # not a comment \\\"quoted\\\" \\\\ \\t\"  # a comment after a string
+port = 7_878 # a comment after an integer
+
+enabled = true
+";
+        let mut config = ConfigFile::parse(text, "test.toml").unwrap();
+        assert_eq!(config.string("name"), Ok(Some("a # not a comment \"quoted\" \\ \t".into())));
+        assert_eq!(config.integer::<u16>("port"), Ok(Some(7878)));
+        assert_eq!(config.boolean("enabled"), Ok(Some(true)));
+        assert_eq!(config.string("missing"), Ok(None));
+        config.finish().unwrap();
+
+        let error = |text: &str| ConfigFile::parse(text, "test.toml").unwrap_err().0;
+        assert_eq!(error("\nname = \"unterminated"), "test.toml:2: unterminated string");
+        assert_eq!(error("name = \"\\x\""), "test.toml:1: unsupported escape sequence \\x");
+        assert_eq!(error("name = bare"), "test.toml:1: invalid value \"bare\" (strings must be in double quotes)");
+        assert_eq!(error("[table]"), "test.toml:1: tables are not supported");
+        assert_eq!(error("port = 1\nport = 2"), "test.toml:2: port is set more than once");
+    }
+
+    #[test]
+    fn values_are_checked_when_taken() {
+        let mut config = ConfigFile::parse("port = 70000\nname = 1\nflag = \"yes\"", "test.toml").unwrap();
+        assert_eq!(config.integer::<u16>("port").unwrap_err().0, "test.toml:1: port: out of range");
+        assert_eq!(config.string("name").unwrap_err().0, "test.toml:2: name: expected a string");
+        assert_eq!(config.boolean("flag").unwrap_err().0, "test.toml:3: flag: expected true or false");
+    }
+
+    #[test]
+    fn unknown_settings_are_rejected() {
+        let mut config = ConfigFile::parse("port = 1\ncolour = \"red\"\nshape = \"round\"", "test.toml").unwrap();
+        assert_eq!(config.integer::<u16>("port"), Ok(Some(1)));
+        // the first one is reported
+        assert_eq!(config.finish().unwrap_err().0, "test.toml:2: colour: unknown setting");
+    }
+
+    #[test]
+    fn only_required_files_must_exist() {
+        let path = std::env::temp_dir().join(format!("chatapp-config-{}-missing.toml", std::process::id()));
+        let mut config = ConfigFile::load(&path, false).unwrap();
+        assert_eq!(config.string("name"), Ok(None));
+        assert!(ConfigFile::load(&path, true).unwrap_err().0.starts_with(&path.display().to_string()));
+    }
+}
@@ -0,0 +1,364 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, prelude::*};
+use std::path::{Path, PathBuf};
+use std::convert::TryInto;
+
+use crate::messages::Message;
+use crate::log::Level;
+
+/// Default size at which the log moves on to a new segment file.
+pub const DEFAULT_MAX_SEGMENT_LEN: u64 = 16 * 1024 * 1024;
+
+/// One entry in the history log.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    /// Milliseconds since the Unix epoch.
+    pub timestamp: u64,
+    /// The room the message was sent in, or the event happened in.
+    pub room: String,
+    /// A StampedChatMessage, or a UserJoined, UserLeft or UserRenamed event.
+    pub message: Message<'static>,
+}
+
+impl Record {
+    fn to_bytes(&self) -> Vec<u8> {
+        let room_len: u32 = self.room.len().try_into().unwrap();
+        let mut bytes = vec![];
+        bytes.extend(&self.timestamp.to_le_bytes());
+        bytes.extend(&room_len.to_le_bytes());
+        bytes.extend(self.room.as_bytes());
+        bytes.extend(self.message.to_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() < 12 {
+            return None;
+        }
+        let timestamp = u64::from_le_bytes(bytes[0..8].try_into().unwrap());
+        let room_len: usize = u32::from_le_bytes(bytes[8..12].try_into().unwrap()).try_into().ok()?;
+        let rest = &bytes[12..];
+        if rest.len() < room_len {
+            return None;
+        }
+        let (room, message) = rest.split_at(room_len);
+        Some(Record {
+            timestamp,
+            room: std::str::from_utf8(room).ok()?.into(),
+            message: Message::from_bytes(message)?.into_owned(),
+        })
+    }
+}
+
+/// Append-only log of relayed chat messages and system events, split into numbered segment files in a directory.
+///
+/// Each record is written as a 4-byte little-endian length, a 4-byte little-endian CRC-32 of the record,
+/// and then the record, in a single write. A crash can therefore only leave a partial record at the end
+/// of the last segment, which is cut off when the log is next opened.
+///
+/// Appending does not fsync, since doing that for every message would slow down everything else;
+/// sync does, once for everything appended since the last one. The server calls it once per pass of its
+/// event loop, so a crash of the whole machine (not just the server) can lose the records of that last pass.
+pub struct HistoryLog {
+    dir: PathBuf,
+    max_segment_len: u64,
+    /// Number of the segment being appended to.
+    segment: u64,
+    file: File,
+    /// Length of the segment being appended to.
+    len: u64,
+    /// Whether anything was appended since the last sync.
+    unsynced: bool,
+    /// One more than the largest StampedChatMessage id in the log, or 0 if there are none.
+    next_message_id: u64,
+}
+
+impl HistoryLog {
+    /// Opens (creating it if necessary) the log in dir, recovering from any partial write at its end.
+    /// Segments are moved on from once they would exceed max_segment_len bytes.
+    pub fn open(dir: impl AsRef<Path>, max_segment_len: u64) -> io::Result<Self> {
+        let dir = dir.as_ref().to_owned();
+        fs::create_dir_all(&dir)?;
+        let segments = list_segments(&dir)?;
+
+        let mut next_message_id = None;
+        // the newest chat message is usually in the last segment, but not necessarily (e.g. only events since rotating)
+        for (i, &segment) in segments.iter().enumerate().rev() {
+            let path = segment_path(&dir, segment);
+            let bytes = fs::read(&path)?;
+            let (records, valid_len, undecodable) = parse_segment(&bytes);
+            if undecodable > 0 {
+                log!(Level::Warn, "History: skipping {} records in {} that could not be decoded", undecodable, path.display());
+            }
+            if valid_len < bytes.len() {
+                if i == segments.len() - 1 {
+                    log!(Level::Warn, "History: discarding {} bytes of partial or corrupt records at the end of {}", bytes.len() - valid_len, path.display());
+                    OpenOptions::new().write(true).open(&path)?.set_len(valid_len as u64)?;
+                } else {
+                    log!(Level::Warn, "History: ignoring {} bytes of corrupt records at the end of {}", bytes.len() - valid_len, path.display());
+                }
+            }
+            next_message_id = records.iter().filter_map(|record| match record.message {
+                Message::StampedChatMessage { id, .. } => Some(id + 1),
+                _ => None,
+            }).max();
+            if next_message_id.is_some() {
+                break;
+            }
+        }
+
+        let segment = segments.last().copied().unwrap_or(0);
+        let file = OpenOptions::new().append(true).create(true).open(segment_path(&dir, segment))?;
+        let len = file.metadata()?.len();
+        Ok(HistoryLog {
+            dir,
+            max_segment_len,
+            segment,
+            file,
+            len,
+            unsynced: false,
+            next_message_id: next_message_id.unwrap_or(0),
+        })
+    }
+
+    /// The id to give the next chat message, so that ids keep increasing across restarts.
+    pub fn next_message_id(&self) -> u64 {
+        self.next_message_id
+    }
+
+    /// Reads up to the last count chat messages in the log, oldest first.
+    pub fn recent_messages(&self, count: usize) -> io::Result<Vec<Record>> {
+        let mut recent: Vec<Record> = vec![];
+        for segment in list_segments(&self.dir)?.into_iter().rev() {
+            if recent.len() >= count {
+                break;
+            }
+            let (records, _, _) = parse_segment(&fs::read(segment_path(&self.dir, segment))?);
+            let mut chat: Vec<Record> = records.into_iter()
+                .filter(|record| matches!(record.message, Message::StampedChatMessage { .. }))
+                .collect();
+            chat.append(&mut recent);
+            recent = chat;
+        }
+        let excess = recent.len().saturating_sub(count);
+        recent.drain(..excess);
+        Ok(recent)
+    }
+
+    /// Appends a record (durably only once synced), first moving on to a new segment if this one is full.
+    pub fn append(&mut self, record: &Record) -> io::Result<()> {
+        let payload = record.to_bytes();
+        let payload_len: u32 = payload.len().try_into().unwrap();
+        let mut bytes = Vec::with_capacity(8 + payload.len());
+        bytes.extend(&payload_len.to_le_bytes());
+        bytes.extend(&crc32(&payload).to_le_bytes());
+        bytes.extend(payload);
+
+        if self.len > 0 && self.len + bytes.len() as u64 > self.max_segment_len {
+            self.rotate()?;
+        }
+        self.file.write_all(&bytes)?;
+        self.unsynced = true;
+        self.len += bytes.len() as u64;
+        if let Message::StampedChatMessage { id, .. } = record.message {
+            self.next_message_id = self.next_message_id.max(id + 1);
+        }
+        Ok(())
+    }
+
+    /// Makes everything appended so far durable.
+    pub fn sync(&mut self) -> io::Result<()> {
+        if self.unsynced {
+            self.file.sync_data()?;
+            self.unsynced = false;
+        }
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        // only the segment being appended to can have unsynced records
+        self.sync()?;
+        let segment = self.segment + 1;
+        self.file = OpenOptions::new().append(true).create(true).open(segment_path(&self.dir, segment))?;
+        self.segment = segment;
+        self.len = 0;
+        Ok(())
+    }
+}
+
+fn segment_path(dir: &Path, segment: u64) -> PathBuf {
+    dir.join(format!("{:08}.log", segment))
+}
+
+/// Numbers of the segment files in dir, in order.
+fn list_segments(dir: &Path) -> io::Result<Vec<u64>> {
+    let mut segments = vec![];
+    for entry in fs::read_dir(dir)? {
+        let file_name = entry?.file_name();
+        let segment = file_name.to_str()
+            .and_then(|name| name.strip_suffix(".log"))
+            .and_then(|number| number.parse().ok());
+        if let Some(segment) = segment {
+            segments.push(segment);
+        }
+    }
+    segments.sort_unstable();
+    Ok(segments)
+}
+
+/// Parses the records of a segment, stopping at the first one that is incomplete or fails its checksum.
+/// Returns them, how many bytes they took up, and how many intact records were skipped because they could
+/// not be decoded (e.g. ones from a later version); cutting the log off there would lose everything after them.
+fn parse_segment(bytes: &[u8]) -> (Vec<Record>, usize, usize) {
+    let mut records = vec![];
+    let mut undecodable = 0;
+    let mut pos = 0;
+    while bytes.len() - pos >= 8 {
+        let len: usize = u32::from_le_bytes(bytes[pos..pos + 4].try_into().unwrap()).try_into().unwrap();
+        let checksum = u32::from_le_bytes(bytes[pos + 4..pos + 8].try_into().unwrap());
+        let payload = match bytes.get(pos + 8..pos + 8 + len) {
+            Some(payload) if crc32(payload) == checksum => payload,
+            _ => break,
+        };
+        match Record::from_bytes(payload) {
+            Some(record) => records.push(record),
+            None => undecodable += 1,
+        };
+        pos += 8 + len;
+    }
+    (records, pos, undecodable)
+}
+
+/// CRC-32 (IEEE 802.3), as used by zlib, PNG, etc.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in bytes {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An empty directory for a test's log, unique to the test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("chatapp-history-{}-{}", std::process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn chat(id: u64) -> Record {
+        let message = Message::StampedChatMessage { id, timestamp: 1000 + id, sender: "alice".into(), text: format!("message {}", id).into() };
+        Record { timestamp: 1000 + id, room: "lobby".into(), message }
+    }
+
+    fn joined(name: &str) -> Record {
+        Record { timestamp: 1, room: "lobby".into(), message: Message::UserJoined { room: "lobby".into(), name: name.to_owned().into() } }
+    }
+
+    /// Appends bytes to the end of the last segment, as a crash or some other program might leave them.
+    fn append_raw(dir: &Path, bytes: &[u8]) {
+        let segment = *list_segments(dir).unwrap().last().unwrap();
+        OpenOptions::new().append(true).open(segment_path(dir, segment)).unwrap().write_all(bytes).unwrap();
+    }
+
+    fn framed(payload: &[u8]) -> Vec<u8> {
+        let len = payload.len() as u32;
+        [&len.to_le_bytes()[..], &crc32(payload).to_le_bytes(), payload].concat()
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = temp_dir("reopen");
+        let mut log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        assert_eq!(log.next_message_id(), 0);
+        for record in &[chat(0), joined("bob"), chat(1)] {
+            log.append(record).unwrap();
+        }
+        log.sync().unwrap();
+        drop(log);
+
+        let log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        assert_eq!(log.next_message_id(), 2);
+        assert_eq!(log.recent_messages(10).unwrap(), [chat(0), chat(1)]);
+        assert_eq!(log.recent_messages(1).unwrap(), [chat(1)]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn partial_and_corrupt_records_at_the_end_are_cut_off() {
+        let dir = temp_dir("torn");
+        let mut log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        log.append(&chat(0)).unwrap();
+        let valid_len = log.len;
+        drop(log);
+
+        // half a record
+        append_raw(&dir, &framed(&chat(1).to_bytes())[..10]);
+        let mut log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        assert_eq!(log.len, valid_len);
+        assert_eq!(fs::metadata(segment_path(&dir, 0)).unwrap().len(), valid_len);
+        // appending carries on where the valid records end
+        log.append(&chat(1)).unwrap();
+        drop(log);
+
+        // a whole record, but with a flipped bit in its checksum
+        let mut bytes = framed(&chat(2).to_bytes());
+        bytes[4] ^= 1;
+        append_raw(&dir, &bytes);
+        let log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        assert_eq!(log.recent_messages(10).unwrap(), [chat(0), chat(1)]);
+        assert_eq!(log.next_message_id(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn undecodable_records_are_skipped_not_cut_off() {
+        let dir = temp_dir("undecodable");
+        let mut log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        log.append(&chat(0)).unwrap();
+        drop(log);
+        // intact (its checksum matches), but not a record this version understands
+        append_raw(&dir, &framed(b"from the future"));
+        let mut log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        log.append(&chat(1)).unwrap();
+        drop(log);
+
+        let log = HistoryLog::open(&dir, DEFAULT_MAX_SEGMENT_LEN).unwrap();
+        assert_eq!(log.recent_messages(10).unwrap(), [chat(0), chat(1)]);
+        assert_eq!(log.next_message_id(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn segments_rotate_and_ids_are_recovered_from_older_ones() {
+        let dir = temp_dir("rotate");
+        let record_len = framed(&chat(0).to_bytes()).len() as u64;
+        // three records to a segment
+        let mut log = HistoryLog::open(&dir, record_len * 3).unwrap();
+        for id in 0..10 {
+            log.append(&chat(id)).unwrap();
+        }
+        assert_eq!(list_segments(&dir).unwrap(), [0, 1, 2, 3]);
+        assert!(fs::metadata(segment_path(&dir, 0)).unwrap().len() <= record_len * 3);
+        // a segment with nothing but events, so the newest id is in an older one
+        for _ in 0..3 {
+            log.append(&joined("bob")).unwrap();
+        }
+        log.append(&joined("carol")).unwrap();
+        assert_eq!(list_segments(&dir).unwrap().len(), 5);
+        log.sync().unwrap();
+        drop(log);
+
+        let log = HistoryLog::open(&dir, record_len * 3).unwrap();
+        assert_eq!(log.next_message_id(), 10);
+        assert_eq!(log.recent_messages(100).unwrap(), (0..10).map(chat).collect::<Vec<_>>());
+        assert_eq!(log.recent_messages(4).unwrap(), (6..10).map(chat).collect::<Vec<_>>());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,49 @@
+//! The chat protocol (see protocol-v1.txt), a client for it, for writing bots and other tools,
+//! and the server, for running one from tests or other programs (see server::Server).
+//!
+//! ```
+//! use chatapp::client::{Client, Event};
+//! use chatapp::messages::{Message, PROTOCOL_VERSION};
+//! # use chatapp::server::{Server, ServerConfig};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # chatapp::set_level(chatapp::Level::Off);
+//! # let server = Server::bind("127.0.0.1:0".parse().unwrap(), ServerConfig::default())?.spawn()?;
+//! # let addr = server.local_addr();
+//! // only for servers that speak version 2; see Client::connect
+//! let mut client = Client::connect(addr, None, PROTOCOL_VERSION)?;
+//! client.change_name("echo-bot").unwrap();
+//! # let mut other = Client::connect(addr, None, PROTOCOL_VERSION)?;
+//! # other.send_chat("hello").unwrap();
+//! # let mut received = None;
+//! // waits for the first chat message from someone else
+//! for event in client.events() {
+//!     if let Event::Message(Message::StampedChatMessage { sender, text, .. }) = event? {
+//!         println!("{}: {}", sender, text);
+//! #       received = Some(text.into_owned());
+//!         break;
+//!     }
+//! }
+//! # assert_eq!(received.as_deref(), Some("hello"));
+//! # Ok(())
+//! # }
+//! ```
+
+#[macro_use]
+mod log;
+pub mod messages;
+pub mod util;
+pub mod sanitize;
+pub mod tls;
+pub mod client;
+pub mod config;
+mod names;
+mod rate_limit;
+mod history;
+mod accounts;
+pub mod server;
+
+/// How much the library (and the programs using it) log to stderr.
+pub use crate::log::{set_level, Level};
+#[doc(hidden)]
+pub use crate::log::enabled as __log_enabled;
@@ -1,15 +1,400 @@
 use std::borrow::Cow;
+use std::convert::TryInto;
+use std::ops::{BitAnd, BitOr};
 
+/// The newest protocol version this implementation speaks.
+pub const PROTOCOL_VERSION: u16 = 2;
+/// The version assumed for peers that never send a Hello (i.e. protocol-v1.txt as originally written).
+pub const BASE_PROTOCOL_VERSION: u16 = 1;
+
+/// The room every client starts in, and the only room clients without Capabilities::ROOMS are ever in.
+pub const DEFAULT_ROOM: &str = "lobby";
+
+/// UserLeft reasons
+/// The client sent a Disconnect.
+pub const LEAVE_DISCONNECTED: u8 = 0;
+/// The connection closed or broke without a Disconnect.
+pub const LEAVE_CONNECTION_LOST: u8 = 1;
+/// The server disconnected the client, e.g. for sending an oversized message, or because an operator kicked it.
+pub const LEAVE_REMOVED: u8 = 2;
+
+/// DirectMessageError reasons
+/// There is no client with the recipient's name.
+pub const DIRECT_NO_SUCH_USER: u8 = 0;
+/// The sender is muted.
+pub const DIRECT_MUTED: u8 = 1;
+/// The sender is sending messages faster than the server allows.
+pub const DIRECT_RATE_LIMITED: u8 = 2;
+
+/// Why a ChatMessage was rejected, as sent in a ChatMessageError.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ChatError {
+    /// 0
+    InvalidUtf8,
+    /// 1; the server disconnects the client after sending this.
+    TooLong,
+    /// 2; an operator muted the client.
+    Muted,
+    /// 3; the client is sending messages faster than the server allows.
+    RateLimited,
+    /// 127
+    Other,
+    /// A reason this implementation does not know, e.g. from a newer peer. Never a value listed above.
+    Unknown(u8),
+}
+
+impl From<u8> for ChatError {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0 => ChatError::InvalidUtf8,
+            1 => ChatError::TooLong,
+            2 => ChatError::Muted,
+            3 => ChatError::RateLimited,
+            127 => ChatError::Other,
+            reason => ChatError::Unknown(reason),
+        }
+    }
+}
+
+impl From<ChatError> for u8 {
+    fn from(reason: ChatError) -> u8 {
+        match reason {
+            ChatError::InvalidUtf8 => 0,
+            ChatError::TooLong => 1,
+            ChatError::Muted => 2,
+            ChatError::RateLimited => 3,
+            ChatError::Other => 127,
+            ChatError::Unknown(reason) => reason,
+        }
+    }
+}
+
+/// Why a NameChangeRequest was denied, as sent in a NameChangeDenial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum NameDenial {
+    /// 0; someone else has the name, or one that only differs in case or look-alike characters.
+    AlreadyExists,
+    /// 1
+    Empty,
+    /// 2
+    TooLong,
+    /// 3
+    InvalidCharacters,
+    /// 4
+    Reserved,
+    /// 5; the name is registered to an account the client is not logged in to.
+    Registered,
+    /// 6; an operator banned the name.
+    Banned,
+    /// 7; the client is changing its name faster than the server allows.
+    RateLimited,
+    /// 127
+    Other,
+    /// A reason this implementation does not know, e.g. from a newer peer. Never a value listed above.
+    Unknown(u8),
+}
+
+impl From<u8> for NameDenial {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0 => NameDenial::AlreadyExists,
+            1 => NameDenial::Empty,
+            2 => NameDenial::TooLong,
+            3 => NameDenial::InvalidCharacters,
+            4 => NameDenial::Reserved,
+            5 => NameDenial::Registered,
+            6 => NameDenial::Banned,
+            7 => NameDenial::RateLimited,
+            127 => NameDenial::Other,
+            reason => NameDenial::Unknown(reason),
+        }
+    }
+}
+
+impl From<NameDenial> for u8 {
+    fn from(reason: NameDenial) -> u8 {
+        match reason {
+            NameDenial::AlreadyExists => 0,
+            NameDenial::Empty => 1,
+            NameDenial::TooLong => 2,
+            NameDenial::InvalidCharacters => 3,
+            NameDenial::Reserved => 4,
+            NameDenial::Registered => 5,
+            NameDenial::Banned => 6,
+            NameDenial::RateLimited => 7,
+            NameDenial::Other => 127,
+            NameDenial::Unknown(reason) => reason,
+        }
+    }
+}
+
+/// Why a RegisterRequest or LoginRequest was denied, as sent in a RegisterDenial or LoginDenial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AccountDenial {
+    /// 0; the name, or one that looks just like it, is already registered.
+    AlreadyRegistered,
+    /// 1; the client's current name is not one it could have asked for (e.g. the one the server assigned).
+    InvalidName,
+    /// 2
+    PasswordTooShort,
+    /// 3; there is no such account, or the password is wrong (deliberately indistinguishable).
+    InvalidCredentials,
+    /// 4; an operator banned the account's name.
+    Banned,
+    /// 5; the client is registering or logging in faster than the server allows.
+    RateLimited,
+    /// 127
+    Other,
+    /// A reason this implementation does not know, e.g. from a newer peer. Never a value listed above.
+    Unknown(u8),
+}
+
+impl From<u8> for AccountDenial {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0 => AccountDenial::AlreadyRegistered,
+            1 => AccountDenial::InvalidName,
+            2 => AccountDenial::PasswordTooShort,
+            3 => AccountDenial::InvalidCredentials,
+            4 => AccountDenial::Banned,
+            5 => AccountDenial::RateLimited,
+            127 => AccountDenial::Other,
+            reason => AccountDenial::Unknown(reason),
+        }
+    }
+}
+
+impl From<AccountDenial> for u8 {
+    fn from(reason: AccountDenial) -> u8 {
+        match reason {
+            AccountDenial::AlreadyRegistered => 0,
+            AccountDenial::InvalidName => 1,
+            AccountDenial::PasswordTooShort => 2,
+            AccountDenial::InvalidCredentials => 3,
+            AccountDenial::Banned => 4,
+            AccountDenial::RateLimited => 5,
+            AccountDenial::Other => 127,
+            AccountDenial::Unknown(reason) => reason,
+        }
+    }
+}
+
+/// What a ModerationRequest asks the server to do to its target.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModerationAction {
+    /// 0; disconnect the client with the target name.
+    Kick,
+    /// 1; keep the target name, or IP address, off the server for the duration.
+    Ban,
+    /// 2; reject chat and direct messages from the client with the target name for the duration.
+    Mute,
+    /// An action this implementation does not know, e.g. from a newer peer. Never a value listed above.
+    Unknown(u8),
+}
+
+impl From<u8> for ModerationAction {
+    fn from(action: u8) -> Self {
+        match action {
+            0 => ModerationAction::Kick,
+            1 => ModerationAction::Ban,
+            2 => ModerationAction::Mute,
+            action => ModerationAction::Unknown(action),
+        }
+    }
+}
+
+impl From<ModerationAction> for u8 {
+    fn from(action: ModerationAction) -> u8 {
+        match action {
+            ModerationAction::Kick => 0,
+            ModerationAction::Ban => 1,
+            ModerationAction::Mute => 2,
+            ModerationAction::Unknown(action) => action,
+        }
+    }
+}
+
+/// Why a ModerationRequest was denied, as sent in a ModerationDenial.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ModerationError {
+    /// 0
+    NotOperator,
+    /// 1; no client has the target name (or the target is an IP address, which can only be banned).
+    NoSuchUser,
+    /// 127
+    Other,
+    /// A reason this implementation does not know, e.g. from a newer peer. Never a value listed above.
+    Unknown(u8),
+}
+
+impl From<u8> for ModerationError {
+    fn from(reason: u8) -> Self {
+        match reason {
+            0 => ModerationError::NotOperator,
+            1 => ModerationError::NoSuchUser,
+            127 => ModerationError::Other,
+            reason => ModerationError::Unknown(reason),
+        }
+    }
+}
+
+impl From<ModerationError> for u8 {
+    fn from(reason: ModerationError) -> u8 {
+        match reason {
+            ModerationError::NotOperator => 0,
+            ModerationError::NoSuchUser => 1,
+            ModerationError::Other => 127,
+            ModerationError::Unknown(reason) => reason,
+        }
+    }
+}
+
+/// Set of optional protocol features, advertised in Hello and Welcome.
+/// Unknown bits are preserved so that they drop out when intersected with the supported set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Hash)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+    pub const NONE: Capabilities = Capabilities(0);
+    /// Room join/part/list messages.
+    pub const ROOMS: Capabilities = Capabilities(1 << 0);
+    /// DirectMessage and DirectMessageError.
+    pub const DIRECT_MESSAGES: Capabilities = Capabilities(1 << 1);
+    /// Roster request/response and UserJoined/UserLeft/UserRenamed events.
+    pub const ROSTER: Capabilities = Capabilities(1 << 2);
+    /// UserJoined/UserLeft/UserRenamed events instead of chat messages like "{name} joined".
+    pub const SYSTEM_EVENTS: Capabilities = Capabilities(1 << 3);
+    /// RelayedChatMessage instead of ChatMessage("{sender}: {text}").
+    pub const RELAYED_MESSAGES: Capabilities = Capabilities(1 << 4);
+    /// StampedChatMessage instead of RelayedChatMessage or ChatMessage("{sender}: {text}").
+    pub const TIMESTAMPS: Capabilities = Capabilities(1 << 5);
+    /// HistoryRequest, and HistoryBatch on joining a room.
+    pub const HISTORY: Capabilities = Capabilities(1 << 6);
+    /// Ping and Pong.
+    pub const KEEPALIVE: Capabilities = Capabilities(1 << 7);
+    /// Registering names with a password, and logging in to them.
+    pub const ACCOUNTS: Capabilities = Capabilities(1 << 8);
+    /// Kicking, banning and muting clients, for operators.
+    pub const MODERATION: Capabilities = Capabilities(1 << 9);
+
+    /// Every capability this implementation knows how to handle.
+    pub const SUPPORTED: Capabilities = Capabilities(
+        Capabilities::ROOMS.0 | Capabilities::DIRECT_MESSAGES.0 | Capabilities::ROSTER.0
+        | Capabilities::SYSTEM_EVENTS.0 | Capabilities::RELAYED_MESSAGES.0 | Capabilities::TIMESTAMPS.0
+        | Capabilities::HISTORY.0 | Capabilities::KEEPALIVE.0 | Capabilities::ACCOUNTS.0
+        | Capabilities::MODERATION.0
+    );
+
+    pub const fn from_bits(bits: u32) -> Self {
+        Capabilities(bits)
+    }
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for Capabilities {
+    type Output = Capabilities;
+    fn bitor(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 | rhs.0)
+    }
+}
+
+impl BitAnd for Capabilities {
+    type Output = Capabilities;
+    fn bitand(self, rhs: Capabilities) -> Capabilities {
+        Capabilities(self.0 & rhs.0)
+    }
+}
+
+/// A chat message in a HistoryBatch; the same as a StampedChatMessage.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryMessage<'a> {
+    pub id: u64,
+    pub timestamp: u64,
+    pub sender: Cow<'a, str>,
+    pub text: Cow<'a, str>,
+}
+
+impl<'a> HistoryMessage<'a> {
+    pub fn into_owned(self) -> HistoryMessage<'static> {
+        HistoryMessage {
+            id: self.id,
+            timestamp: self.timestamp,
+            sender: Cow::Owned(self.sender.into_owned()),
+            text: Cow::Owned(self.text.into_owned()),
+        }
+    }
+}
+
+#[allow(clippy::enum_variant_names)] // variant names mirror protocol-v1.txt
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Message<'a> {
     NameAssignment(Cow<'a, str>),
+    Hello { version: u16, capabilities: Capabilities },
+    /// max_frame_len is the longest frame (not counting the length prefix) the server will accept.
+    Welcome { version: u16, capabilities: Capabilities, max_frame_len: u32 },
+    /// Must be answered with a Pong carrying the same value.
+    Ping(u64),
+    Pong(u64),
+
+    RosterRequest,
+    /// The names of everyone connected to the server, in no particular order.
+    Roster(Vec<Cow<'a, str>>),
+    /// room is where it happened, for clients that only want to hear about their own room.
+    UserJoined { room: Cow<'a, str>, name: Cow<'a, str> },
+    /// reason is one of the LEAVE_* constants.
+    UserLeft { room: Cow<'a, str>, name: Cow<'a, str>, reason: u8 },
+    UserRenamed { room: Cow<'a, str>, old_name: Cow<'a, str>, new_name: Cow<'a, str> },
 
     ChatMessage(Cow<'a, str>),
-    ChatMessageError(u8),
+    ChatMessageError(ChatError),
+    /// Client -> server: peer is the recipient. Server -> client: peer is the sender.
+    DirectMessage { peer: Cow<'a, str>, text: Cow<'a, str> },
+    /// A DirectMessage could not be delivered to recipient. reason is one of the DIRECT_* constants.
+    DirectMessageError { reason: u8, recipient: Cow<'a, str> },
+    /// A chat message from another client, relayed by the server.
+    RelayedChatMessage { sender: Cow<'a, str>, text: Cow<'a, str> },
+    /// A RelayedChatMessage with a server-assigned id (increasing by at least one for each message)
+    /// and timestamp (milliseconds since the Unix epoch, UTC).
+    StampedChatMessage { id: u64, timestamp: u64, sender: Cow<'a, str>, text: Cow<'a, str> },
+    /// Asks for up to count chat messages in the client's room with ids less than before_id.
+    HistoryRequest { before_id: u64, count: u32 },
+    /// Chat messages in room, oldest first. more is whether the server has older ones.
+    HistoryBatch { room: Cow<'a, str>, messages: Vec<HistoryMessage<'a>>, more: bool },
+
+    RoomJoinRequest(Cow<'a, str>),
+    /// The client is now in the named room.
+    RoomJoined(Cow<'a, str>),
+    RoomJoinDenial(u8),
+    /// Leave the current room for DEFAULT_ROOM.
+    RoomPartRequest,
+    RoomListRequest,
+    /// Every room with at least one member, and how many members it has.
+    RoomList(Vec<(Cow<'a, str>, u32)>),
 
     NameChangeRequest(Cow<'a, str>),
     NameChangeApproval,
-    NameChangeDenial(u8),
+    NameChangeDenial(NameDenial),
+    /// Registers the client's current name, so that from then on only whoever knows the password can take it.
+    RegisterRequest { password: Cow<'a, str> },
+    RegisterApproval,
+    RegisterDenial(AccountDenial),
+    /// Takes a registered name; the client keeps being logged in to it until it disconnects.
+    LoginRequest { name: Cow<'a, str>, password: Cow<'a, str> },
+    /// The client's name is now the account's name (as registered, which may differ in case from the one asked for).
+    LoginApproval(Cow<'a, str>),
+    LoginDenial(AccountDenial),
+
+    /// Asks the server to kick, ban or mute target, which is a name, or for bans also an IP address.
+    /// A ban or mute lasts duration_secs; 0 lifts an earlier one instead. reason may be empty.
+    ModerationRequest { action: ModerationAction, target: Cow<'a, str>, duration_secs: u32, reason: Cow<'a, str> },
+    ModerationDenial(ModerationError),
 
     Disconnect,
 }
@@ -19,25 +404,174 @@ impl<'a> Message<'a> {
         use Message::*;
         match self {
             NameAssignment(_) => 0,
+            Hello { .. } => 1,
+            Welcome { .. } => 2,
+            Ping(_) => 3,
+            Pong(_) => 4,
+            RosterRequest => 32,
+            Roster(_) => 33,
+            UserJoined { .. } => 34,
+            UserLeft { .. } => 35,
+            UserRenamed { .. } => 36,
             ChatMessage(_) => 64,
             ChatMessageError(_) => 65,
+            DirectMessage { .. } => 66,
+            DirectMessageError { .. } => 67,
+            RelayedChatMessage { .. } => 68,
+            StampedChatMessage { .. } => 69,
+            HistoryRequest { .. } => 70,
+            HistoryBatch { .. } => 71,
+            RoomJoinRequest(_) => 96,
+            RoomJoined(_) => 97,
+            RoomJoinDenial(_) => 98,
+            RoomPartRequest => 99,
+            RoomListRequest => 100,
+            RoomList(_) => 101,
             NameChangeRequest(_) => 128,
             NameChangeApproval => 129,
             NameChangeDenial(_) => 130,
+            RegisterRequest { .. } => 131,
+            RegisterApproval => 132,
+            RegisterDenial(_) => 133,
+            LoginRequest { .. } => 134,
+            LoginApproval(_) => 135,
+            LoginDenial(_) => 136,
+            ModerationRequest { .. } => 160,
+            ModerationDenial(_) => 161,
             Disconnect => 255,
         }
     }
 
     pub fn from_bytes(msg: &'a [u8]) -> Option<Self> {
         use Message::*;
-        if msg.len() < 1 { return None; }
+        if msg.is_empty() { return None; }
         Some(match msg.split_at(1) {
             (&[0], name) => NameAssignment(std::str::from_utf8(name).ok()?.into()),
+            (&[1], rest) if rest.len() == 6 => {
+                let (version, capabilities) = parse_version_and_capabilities(rest);
+                Hello { version, capabilities }
+            },
+            (&[2], rest) if rest.len() == 10 => {
+                let (version, capabilities) = parse_version_and_capabilities(&rest[..6]);
+                let max_frame_len = u32::from_le_bytes(rest[6..10].try_into().unwrap());
+                Welcome { version, capabilities, max_frame_len }
+            },
+            (&[3], rest) if rest.len() == 8 => Ping(u64::from_le_bytes(rest.try_into().unwrap())),
+            (&[4], rest) if rest.len() == 8 => Pong(u64::from_le_bytes(rest.try_into().unwrap())),
+            (&[32], &[]) => RosterRequest,
+            (&[33], mut rest) => {
+                let mut names = vec![];
+                while !rest.is_empty() {
+                    names.push(take_str(&mut rest)?.into());
+                }
+                Roster(names)
+            },
+            (&[34], mut rest) => {
+                let room = take_str(&mut rest)?;
+                let name = std::str::from_utf8(rest).ok()?;
+                UserJoined { room: room.into(), name: name.into() }
+            },
+            (&[35], rest) if !rest.is_empty() => {
+                let reason = rest[0];
+                let mut rest = &rest[1..];
+                let room = take_str(&mut rest)?;
+                let name = std::str::from_utf8(rest).ok()?;
+                UserLeft { room: room.into(), name: name.into(), reason }
+            },
+            (&[36], mut rest) => {
+                let room = take_str(&mut rest)?;
+                let old_name = take_str(&mut rest)?;
+                let new_name = std::str::from_utf8(rest).ok()?;
+                UserRenamed { room: room.into(), old_name: old_name.into(), new_name: new_name.into() }
+            },
             (&[64], message) => ChatMessage(std::str::from_utf8(message).ok()?.into()),
-            (&[65], &[error]) => ChatMessageError(error),
+            (&[65], &[error]) => ChatMessageError(error.into()),
+            (&[66], mut rest) => {
+                let peer = take_str(&mut rest)?;
+                let text = std::str::from_utf8(rest).ok()?;
+                DirectMessage { peer: peer.into(), text: text.into() }
+            },
+            (&[67], rest) if !rest.is_empty() => {
+                let recipient = std::str::from_utf8(&rest[1..]).ok()?;
+                DirectMessageError { reason: rest[0], recipient: recipient.into() }
+            },
+            (&[68], mut rest) => {
+                let sender = take_str(&mut rest)?;
+                let text = take_str(&mut rest)?;
+                if !rest.is_empty() {
+                    return None;
+                }
+                RelayedChatMessage { sender: sender.into(), text: text.into() }
+            },
+            (&[69], mut rest) => {
+                let id = take_u64(&mut rest)?;
+                let timestamp = take_u64(&mut rest)?;
+                let sender = take_str(&mut rest)?;
+                let text = take_str(&mut rest)?;
+                if !rest.is_empty() {
+                    return None;
+                }
+                StampedChatMessage { id, timestamp, sender: sender.into(), text: text.into() }
+            },
+            (&[70], mut rest) if rest.len() == 12 => {
+                let before_id = take_u64(&mut rest)?;
+                let count = take_u32(&mut rest)?;
+                HistoryRequest { before_id, count }
+            },
+            (&[71], rest) if !rest.is_empty() => {
+                let more = match rest[0] {
+                    0 => false,
+                    1 => true,
+                    _ => return None,
+                };
+                let mut rest = &rest[1..];
+                let room = take_str(&mut rest)?;
+                let mut messages = vec![];
+                while !rest.is_empty() {
+                    let id = take_u64(&mut rest)?;
+                    let timestamp = take_u64(&mut rest)?;
+                    let sender = take_str(&mut rest)?;
+                    let text = take_str(&mut rest)?;
+                    messages.push(HistoryMessage { id, timestamp, sender: sender.into(), text: text.into() });
+                }
+                HistoryBatch { room: room.into(), messages, more }
+            },
+            (&[96], name) => RoomJoinRequest(std::str::from_utf8(name).ok()?.into()),
+            (&[97], name) => RoomJoined(std::str::from_utf8(name).ok()?.into()),
+            (&[98], &[error]) => RoomJoinDenial(error),
+            (&[99], &[]) => RoomPartRequest,
+            (&[100], &[]) => RoomListRequest,
+            (&[101], mut rest) => {
+                let mut rooms = vec![];
+                while !rest.is_empty() {
+                    let members = take_u32(&mut rest)?;
+                    let name = take_str(&mut rest)?;
+                    rooms.push((name.into(), members));
+                }
+                RoomList(rooms)
+            },
             (&[128], name) => NameChangeRequest(std::str::from_utf8(name).ok()?.into()),
             (&[129], &[]) => NameChangeApproval,
-            (&[130], &[error]) => NameChangeDenial(error),
+            (&[130], &[error]) => NameChangeDenial(error.into()),
+            (&[131], password) => RegisterRequest { password: std::str::from_utf8(password).ok()?.into() },
+            (&[132], &[]) => RegisterApproval,
+            (&[133], &[error]) => RegisterDenial(error.into()),
+            (&[134], mut rest) => {
+                let name = take_str(&mut rest)?;
+                let password = std::str::from_utf8(rest).ok()?;
+                LoginRequest { name: name.into(), password: password.into() }
+            },
+            (&[135], name) => LoginApproval(std::str::from_utf8(name).ok()?.into()),
+            (&[136], &[error]) => LoginDenial(error.into()),
+            (&[160], rest) if rest.len() >= 5 => {
+                let action = rest[0].into();
+                let mut rest = &rest[1..];
+                let duration_secs = take_u32(&mut rest)?;
+                let target = take_str(&mut rest)?;
+                let reason = std::str::from_utf8(rest).ok()?;
+                ModerationRequest { action, target: target.into(), duration_secs, reason: reason.into() }
+            },
+            (&[161], &[error]) => ModerationDenial(error.into()),
             (&[255], &[]) => Disconnect,
             _ => return None,
         })
@@ -51,36 +585,385 @@ impl<'a> Message<'a> {
                 bytes.reserve(name.len());
                 bytes.extend(name.as_bytes());
             },
+            Hello { version, capabilities } => {
+                bytes.extend(&version.to_le_bytes());
+                bytes.extend(&capabilities.bits().to_le_bytes());
+            },
+            Welcome { version, capabilities, max_frame_len } => {
+                bytes.extend(&version.to_le_bytes());
+                bytes.extend(&capabilities.bits().to_le_bytes());
+                bytes.extend(&max_frame_len.to_le_bytes());
+            },
+            Ping(value) | Pong(value) => {
+                bytes.extend(&value.to_le_bytes());
+            },
+            RosterRequest => {},
+            Roster(names) => {
+                for name in names {
+                    push_field(&mut bytes, name.as_bytes());
+                }
+            },
+            UserJoined { room, name } => {
+                push_field(&mut bytes, room.as_bytes());
+                bytes.extend(name.as_bytes());
+            },
+            UserLeft { room, name, reason } => {
+                bytes.push(*reason);
+                push_field(&mut bytes, room.as_bytes());
+                bytes.extend(name.as_bytes());
+            },
+            UserRenamed { room, old_name, new_name } => {
+                push_field(&mut bytes, room.as_bytes());
+                push_field(&mut bytes, old_name.as_bytes());
+                bytes.extend(new_name.as_bytes());
+            },
             ChatMessage(message) => {
                 bytes.reserve(message.len());
                 bytes.extend(message.as_bytes());
             },
             ChatMessageError(error) => {
+                bytes.push((*error).into());
+            },
+            DirectMessage { peer, text } => {
+                push_field(&mut bytes, peer.as_bytes());
+                bytes.extend(text.as_bytes());
+            },
+            DirectMessageError { reason, recipient } => {
+                bytes.push(*reason);
+                bytes.extend(recipient.as_bytes());
+            },
+            RelayedChatMessage { sender, text } => {
+                push_field(&mut bytes, sender.as_bytes());
+                push_field(&mut bytes, text.as_bytes());
+            },
+            StampedChatMessage { id, timestamp, sender, text } => {
+                bytes.extend(&id.to_le_bytes());
+                bytes.extend(&timestamp.to_le_bytes());
+                push_field(&mut bytes, sender.as_bytes());
+                push_field(&mut bytes, text.as_bytes());
+            },
+            HistoryRequest { before_id, count } => {
+                bytes.extend(&before_id.to_le_bytes());
+                bytes.extend(&count.to_le_bytes());
+            },
+            HistoryBatch { room, messages, more } => {
+                bytes.push(*more as u8);
+                push_field(&mut bytes, room.as_bytes());
+                for message in messages {
+                    bytes.extend(&message.id.to_le_bytes());
+                    bytes.extend(&message.timestamp.to_le_bytes());
+                    push_field(&mut bytes, message.sender.as_bytes());
+                    push_field(&mut bytes, message.text.as_bytes());
+                }
+            },
+            RoomJoinRequest(name) | RoomJoined(name) => {
+                bytes.extend(name.as_bytes());
+            },
+            RoomJoinDenial(error) => {
                 bytes.push(*error);
             },
+            RoomPartRequest => {},
+            RoomListRequest => {},
+            RoomList(rooms) => {
+                for (name, members) in rooms {
+                    bytes.extend(&members.to_le_bytes());
+                    push_field(&mut bytes, name.as_bytes());
+                }
+            },
             NameChangeRequest(name) => {
                 bytes.reserve(name.len());
                 bytes.extend(name.as_bytes());
             },
             NameChangeApproval => {},
             NameChangeDenial(error) => {
-                bytes.push(*error);
+                bytes.push((*error).into());
+            },
+            RegisterRequest { password } => {
+                bytes.extend(password.as_bytes());
+            },
+            RegisterApproval => {},
+            RegisterDenial(error) | LoginDenial(error) => {
+                bytes.push((*error).into());
+            },
+            LoginRequest { name, password } => {
+                push_field(&mut bytes, name.as_bytes());
+                bytes.extend(password.as_bytes());
+            },
+            LoginApproval(name) => {
+                bytes.extend(name.as_bytes());
+            },
+            ModerationRequest { action, target, duration_secs, reason } => {
+                bytes.push((*action).into());
+                bytes.extend(&duration_secs.to_le_bytes());
+                push_field(&mut bytes, target.as_bytes());
+                bytes.extend(reason.as_bytes());
+            },
+            ModerationDenial(error) => {
+                bytes.push((*error).into());
             },
             Disconnect => {},
         };
         bytes
     }
-    #[allow(dead_code)] // only used in client
     pub fn into_owned(self) -> Message<'static> {
         use Message::*;
         match self {
             NameAssignment(name) => NameAssignment(Cow::Owned(name.into_owned())),
+            Hello { version, capabilities } => Hello { version, capabilities },
+            Welcome { version, capabilities, max_frame_len } => Welcome { version, capabilities, max_frame_len },
+            Ping(value) => Ping(value),
+            Pong(value) => Pong(value),
+            RosterRequest => RosterRequest,
+            Roster(names) => Roster(names.into_iter().map(|name| Cow::Owned(name.into_owned())).collect()),
+            UserJoined { room, name } => UserJoined { room: Cow::Owned(room.into_owned()), name: Cow::Owned(name.into_owned()) },
+            UserLeft { room, name, reason } => UserLeft {
+                room: Cow::Owned(room.into_owned()),
+                name: Cow::Owned(name.into_owned()),
+                reason,
+            },
+            UserRenamed { room, old_name, new_name } => UserRenamed {
+                room: Cow::Owned(room.into_owned()),
+                old_name: Cow::Owned(old_name.into_owned()),
+                new_name: Cow::Owned(new_name.into_owned()),
+            },
             ChatMessage(s) => ChatMessage(Cow::Owned(s.into_owned())),
             ChatMessageError(error) => ChatMessageError(error),
+            DirectMessage { peer, text } => DirectMessage {
+                peer: Cow::Owned(peer.into_owned()),
+                text: Cow::Owned(text.into_owned()),
+            },
+            DirectMessageError { reason, recipient } => DirectMessageError {
+                reason,
+                recipient: Cow::Owned(recipient.into_owned()),
+            },
+            RelayedChatMessage { sender, text } => RelayedChatMessage {
+                sender: Cow::Owned(sender.into_owned()),
+                text: Cow::Owned(text.into_owned()),
+            },
+            StampedChatMessage { id, timestamp, sender, text } => StampedChatMessage {
+                id,
+                timestamp,
+                sender: Cow::Owned(sender.into_owned()),
+                text: Cow::Owned(text.into_owned()),
+            },
+            HistoryRequest { before_id, count } => HistoryRequest { before_id, count },
+            HistoryBatch { room, messages, more } => HistoryBatch {
+                room: Cow::Owned(room.into_owned()),
+                messages: messages.into_iter().map(HistoryMessage::into_owned).collect(),
+                more,
+            },
+            RoomJoinRequest(name) => RoomJoinRequest(Cow::Owned(name.into_owned())),
+            RoomJoined(name) => RoomJoined(Cow::Owned(name.into_owned())),
+            RoomJoinDenial(reason) => RoomJoinDenial(reason),
+            RoomPartRequest => RoomPartRequest,
+            RoomListRequest => RoomListRequest,
+            RoomList(rooms) => RoomList(
+                rooms.into_iter().map(|(name, members)| (Cow::Owned(name.into_owned()), members)).collect()
+            ),
             NameChangeRequest(name) => NameChangeRequest(Cow::Owned(name.into_owned())),
             NameChangeApproval => NameChangeApproval,
             NameChangeDenial(reason) => NameChangeDenial(reason),
+            RegisterRequest { password } => RegisterRequest { password: Cow::Owned(password.into_owned()) },
+            RegisterApproval => RegisterApproval,
+            RegisterDenial(reason) => RegisterDenial(reason),
+            LoginRequest { name, password } => LoginRequest {
+                name: Cow::Owned(name.into_owned()),
+                password: Cow::Owned(password.into_owned()),
+            },
+            LoginApproval(name) => LoginApproval(Cow::Owned(name.into_owned())),
+            LoginDenial(reason) => LoginDenial(reason),
+            ModerationRequest { action, target, duration_secs, reason } => ModerationRequest {
+                action,
+                target: Cow::Owned(target.into_owned()),
+                duration_secs,
+                reason: Cow::Owned(reason.into_owned()),
+            },
+            ModerationDenial(reason) => ModerationDenial(reason),
             Disconnect => Disconnect,
         }
     }
 }
+
+/// Appends a field prefixed with its 4-byte little-endian length.
+fn push_field(bytes: &mut Vec<u8>, field: &[u8]) {
+    let len: u32 = field.len().try_into().unwrap();
+    bytes.extend(&len.to_le_bytes());
+    bytes.extend(field);
+}
+
+/// Splits a 4-byte little-endian integer off the front of bytes.
+fn take_u32(bytes: &mut &[u8]) -> Option<u32> {
+    if bytes.len() < 4 {
+        return None;
+    }
+    let (value, rest) = bytes.split_at(4);
+    *bytes = rest;
+    Some(u32::from_le_bytes(value.try_into().unwrap()))
+}
+
+/// Splits an 8-byte little-endian integer off the front of bytes.
+fn take_u64(bytes: &mut &[u8]) -> Option<u64> {
+    if bytes.len() < 8 {
+        return None;
+    }
+    let (value, rest) = bytes.split_at(8);
+    *bytes = rest;
+    Some(u64::from_le_bytes(value.try_into().unwrap()))
+}
+
+/// Splits a field written by push_field off the front of bytes.
+fn take_field<'a>(bytes: &mut &'a [u8]) -> Option<&'a [u8]> {
+    let len: usize = take_u32(bytes)?.try_into().ok()?;
+    if bytes.len() < len {
+        return None;
+    }
+    let (field, rest) = bytes.split_at(len);
+    *bytes = rest;
+    Some(field)
+}
+
+/// Like take_field, for fields that must be UTF-8.
+fn take_str<'a>(bytes: &mut &'a [u8]) -> Option<&'a str> {
+    std::str::from_utf8(take_field(bytes)?).ok()
+}
+
+/// Splits the version and capabilities (6 bytes) at the start of a Hello or Welcome message.
+fn parse_version_and_capabilities(rest: &[u8]) -> (u16, Capabilities) {
+    let version = u16::from_le_bytes(rest[0..2].try_into().unwrap());
+    let capabilities = u32::from_le_bytes(rest[2..6].try_into().unwrap());
+    (version, Capabilities::from_bits(capabilities))
+}
+
+/// Picks the protocol version and capabilities to use with a peer that sent the given Hello.
+/// Clients never send a version below BASE_PROTOCOL_VERSION, but clamp anyway.
+pub fn negotiate(version: u16, capabilities: Capabilities) -> (u16, Capabilities) {
+    let version = version.clamp(BASE_PROTOCOL_VERSION, PROTOCOL_VERSION);
+    (version, capabilities & Capabilities::SUPPORTED)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(msg: Message) {
+        let bytes = msg.to_bytes();
+        assert_eq!(bytes[0], msg.message_type());
+        assert_eq!(Message::from_bytes(&bytes), Some(msg));
+    }
+
+    #[test]
+    fn relayed_chat_message_round_trip() {
+        round_trip(Message::RelayedChatMessage { sender: "alice".into(), text: "hello".into() });
+        round_trip(Message::RelayedChatMessage { sender: "".into(), text: "".into() });
+        round_trip(Message::RelayedChatMessage { sender: "älice: fake".into(), text: "bob: hi\n".into() });
+    }
+
+    #[test]
+    fn relayed_chat_message_keeps_fields_separate() {
+        // a sender name that looks like the v1 "{sender}: {text}" format stays in the sender field
+        let bytes = Message::RelayedChatMessage { sender: "alice: fake".into(), text: "x".into() }.to_bytes();
+        match Message::from_bytes(&bytes) {
+            Some(Message::RelayedChatMessage { sender, text }) => {
+                assert_eq!(sender, "alice: fake");
+                assert_eq!(text, "x");
+            },
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn reasons_match_spec_numbering() {
+        assert_eq!(Message::NameChangeDenial(NameDenial::AlreadyExists).to_bytes(), [130, 0]);
+        assert_eq!(Message::NameChangeDenial(NameDenial::Empty).to_bytes(), [130, 1]);
+        assert_eq!(Message::NameChangeDenial(NameDenial::Other).to_bytes(), [130, 127]);
+        assert_eq!(Message::ChatMessageError(ChatError::InvalidUtf8).to_bytes(), [65, 0]);
+        assert_eq!(Message::ChatMessageError(ChatError::TooLong).to_bytes(), [65, 1]);
+        assert_eq!(Message::ChatMessageError(ChatError::RateLimited).to_bytes(), [65, 3]);
+        assert_eq!(Message::NameChangeDenial(NameDenial::Registered).to_bytes(), [130, 5]);
+        assert_eq!(Message::LoginDenial(AccountDenial::InvalidCredentials).to_bytes(), [136, 3]);
+        // every byte decodes to something, and encodes back to the same byte
+        for reason in 0..=255u8 {
+            assert_eq!(u8::from(NameDenial::from(reason)), reason);
+            assert_eq!(u8::from(ChatError::from(reason)), reason);
+            assert_eq!(u8::from(AccountDenial::from(reason)), reason);
+            assert_eq!(u8::from(ModerationAction::from(reason)), reason);
+            assert_eq!(u8::from(ModerationError::from(reason)), reason);
+        }
+        assert_eq!(NameDenial::from(8), NameDenial::Unknown(8));
+        assert_eq!(ChatError::from(128), ChatError::Unknown(128));
+    }
+
+    #[test]
+    fn relayed_chat_message_rejects_malformed_fields() {
+        let bytes = Message::RelayedChatMessage { sender: "alice".into(), text: "hello".into() }.to_bytes();
+        // truncated anywhere
+        for len in 1..bytes.len() {
+            assert_eq!(Message::from_bytes(&bytes[..len]), None, "truncated to {} bytes", len);
+        }
+        // trailing garbage
+        let mut extended = bytes.clone();
+        extended.push(0);
+        assert_eq!(Message::from_bytes(&extended), None);
+        // sender length larger than the message
+        let mut bad_len = bytes;
+        bad_len[1..5].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(Message::from_bytes(&bad_len), None);
+        // invalid UTF-8 in a field
+        let mut bytes = vec![68];
+        push_field(&mut bytes, &[0xff]);
+        push_field(&mut bytes, b"text");
+        assert_eq!(Message::from_bytes(&bytes), None);
+    }
+
+    #[test]
+    fn other_messages_round_trip() {
+        use Message::*;
+        let caps = Capabilities::ROOMS | Capabilities::RELAYED_MESSAGES;
+        round_trip(NameAssignment("127.0.0.1:1234".into()));
+        round_trip(Hello { version: PROTOCOL_VERSION, capabilities: caps });
+        round_trip(Welcome { version: PROTOCOL_VERSION, capabilities: caps, max_frame_len: 65536 });
+        round_trip(Ping(0x0123_4567_89ab_cdef));
+        round_trip(Pong(u64::MAX));
+        round_trip(RosterRequest);
+        round_trip(Roster(vec!["alice".into(), "bob".into()]));
+        round_trip(Roster(vec![]));
+        round_trip(UserJoined { room: "lobby".into(), name: "alice".into() });
+        round_trip(UserLeft { room: "lobby".into(), name: "alice".into(), reason: LEAVE_CONNECTION_LOST });
+        round_trip(UserRenamed { room: "lobby".into(), old_name: "alice".into(), new_name: "alicia".into() });
+        round_trip(ChatMessage("hello".into()));
+        round_trip(ChatMessageError(ChatError::TooLong));
+        round_trip(DirectMessage { peer: "bob".into(), text: "psst".into() });
+        round_trip(DirectMessageError { reason: 0, recipient: "nobody".into() });
+        round_trip(StampedChatMessage { id: 7, timestamp: 1_700_000_000_000, sender: "alice".into(), text: "hi".into() });
+        round_trip(HistoryRequest { before_id: u64::MAX, count: 50 });
+        round_trip(HistoryBatch {
+            room: "lobby".into(),
+            messages: vec![
+                HistoryMessage { id: 1, timestamp: 10, sender: "alice".into(), text: "hi".into() },
+                HistoryMessage { id: 3, timestamp: 12, sender: "bob".into(), text: "".into() },
+            ],
+            more: true,
+        });
+        round_trip(HistoryBatch { room: "dev".into(), messages: vec![], more: false });
+        round_trip(RoomJoinRequest("dev".into()));
+        round_trip(RoomJoined("dev".into()));
+        round_trip(RoomJoinDenial(0));
+        round_trip(RoomPartRequest);
+        round_trip(RoomListRequest);
+        round_trip(RoomList(vec![("dev".into(), 2), ("lobby".into(), 5)]));
+        round_trip(NameChangeRequest("alice".into()));
+        round_trip(NameChangeApproval);
+        round_trip(NameChangeDenial(NameDenial::AlreadyExists));
+        round_trip(NameChangeDenial(NameDenial::Unknown(200)));
+        round_trip(RegisterRequest { password: "correct horse".into() });
+        round_trip(RegisterApproval);
+        round_trip(RegisterDenial(AccountDenial::PasswordTooShort));
+        round_trip(LoginRequest { name: "alice".into(), password: "correct horse".into() });
+        round_trip(LoginApproval("Alice".into()));
+        round_trip(LoginDenial(AccountDenial::Unknown(200)));
+        round_trip(ModerationRequest { action: ModerationAction::Ban, target: "10.0.0.1".into(), duration_secs: 3600, reason: "spam".into() });
+        round_trip(ModerationRequest { action: ModerationAction::Unknown(9), target: "".into(), duration_secs: 0, reason: "".into() });
+        round_trip(ModerationDenial(ModerationError::NotOperator));
+        round_trip(Disconnect);
+    }
+}
@@ -0,0 +1,138 @@
+use unicode_width::UnicodeWidthStr;
+
+use crate::messages::NameDenial;
+
+/// Which names clients may ask for (names the server assigns itself are not checked).
+#[derive(Debug, Clone)]
+pub struct NamePolicy {
+    /// Widest a name may be, in terminal columns.
+    pub max_width: usize,
+    /// Whether letters and digits may be from any script, rather than only ASCII.
+    pub allow_unicode: bool,
+    /// Characters allowed besides letters and digits.
+    pub allowed_punctuation: String,
+    /// Whether single spaces are allowed between the other characters.
+    pub allow_spaces: bool,
+    /// Names nobody may take, or anything that looks like them.
+    pub reserved: Vec<String>,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        NamePolicy {
+            max_width: 20,
+            allow_unicode: true,
+            allowed_punctuation: "-_.'".into(),
+            allow_spaces: false,
+            reserved: ["server", "admin", "administrator", "moderator", "operator", "root", "system"]
+                .iter().map(|&name| name.into()).collect(),
+        }
+    }
+}
+
+impl NamePolicy {
+    /// Checks a non-empty requested name against everything except it being taken.
+    pub fn check(&self, name: &str) -> Result<(), NameDenial> {
+        if name.width() > self.max_width {
+            return Err(NameDenial::TooLong);
+        }
+        let allowed = |c: char| {
+            (if self.allow_unicode { c.is_alphanumeric() } else { c.is_ascii_alphanumeric() })
+                || self.allowed_punctuation.contains(c)
+        };
+        // spaces only ever separate words
+        let words_ok = match self.allow_spaces {
+            true => name.split(' ').all(|word| !word.is_empty() && word.chars().all(allowed)),
+            false => name.chars().all(allowed),
+        };
+        if !words_ok {
+            return Err(NameDenial::InvalidCharacters);
+        }
+        let name_skeleton = skeleton(name);
+        if self.reserved.iter().any(|reserved| skeleton(reserved) == name_skeleton) {
+            return Err(NameDenial::Reserved);
+        }
+        Ok(())
+    }
+}
+
+/// Folds case and some easily confused characters (after Unicode TR39 "skeletons", but far less thorough),
+/// so that names that would look the same to users compare equal.
+pub fn skeleton(name: &str) -> String {
+    name.chars().flat_map(char::to_lowercase).map(|c| match c {
+        '0' | 'о' | 'ο' => 'o',
+        '1' | 'i' | 'ı' | 'і' => 'l',
+        'а' | 'α' => 'a',
+        'е' => 'e',
+        'р' | 'ρ' => 'p',
+        'с' => 'c',
+        'х' | 'χ' => 'x',
+        'у' => 'y',
+        'ѕ' => 's',
+        'ј' => 'j',
+        'ԁ' => 'd',
+        'ν' => 'v',
+        'к' | 'κ' => 'k',
+        'һ' => 'h',
+        'ԛ' => 'q',
+        'ԝ' => 'w',
+        c => c,
+    }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn names_are_limited_by_display_width() {
+        let policy = NamePolicy { max_width: 6, ..NamePolicy::default() };
+        assert_eq!(policy.check("abcdef"), Ok(()));
+        assert_eq!(policy.check("abcdefg"), Err(NameDenial::TooLong));
+        // each of these takes two columns
+        assert_eq!(policy.check("漢字名"), Ok(()));
+        assert_eq!(policy.check("漢字名前"), Err(NameDenial::TooLong));
+    }
+
+    #[test]
+    fn letters_can_be_limited_to_ascii() {
+        let unicode = NamePolicy::default();
+        let ascii = NamePolicy { allow_unicode: false, ..NamePolicy::default() };
+        for name in &["café", "Ελένη", "名前"] {
+            assert_eq!(unicode.check(name), Ok(()));
+            assert_eq!(ascii.check(name), Err(NameDenial::InvalidCharacters));
+        }
+        assert_eq!(ascii.check("o'brien-2.0_x"), Ok(()));
+        assert_eq!(ascii.check("a!b"), Err(NameDenial::InvalidCharacters));
+    }
+
+    #[test]
+    fn spaces_only_separate_words() {
+        let spaces = NamePolicy { allow_spaces: true, ..NamePolicy::default() };
+        assert_eq!(spaces.check("two words"), Ok(()));
+        for name in &[" leading", "trailing ", "double  space", " "] {
+            assert_eq!(spaces.check(name), Err(NameDenial::InvalidCharacters), "{:?}", name);
+        }
+        assert_eq!(NamePolicy::default().check("two words"), Err(NameDenial::InvalidCharacters));
+    }
+
+    #[test]
+    fn invisible_characters_are_rejected() {
+        let policy = NamePolicy { allow_spaces: true, ..NamePolicy::default() };
+        for name in &["ad\u{200b}min", "bob\u{200d}", "\u{2060}bob", "bob\u{feff}", "a\u{202e}b", "a\tb"] {
+            assert_eq!(policy.check(name), Err(NameDenial::InvalidCharacters), "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn look_alikes_of_reserved_names_are_reserved() {
+        let policy = NamePolicy::default();
+        // "Adm1n", Cyrillic "а", Greek "ο" in "rοot"
+        for name in &["admin", "ADMIN", "Adm1n", "adm\u{131}n", "\u{430}dmin", "r\u{3bf}ot", "R00T"] {
+            assert_eq!(policy.check(name), Err(NameDenial::Reserved), "{:?}", name);
+        }
+        assert_eq!(policy.check("admins"), Ok(()));
+        assert_eq!(skeleton("Adm1n"), skeleton("\u{430}dmin"));
+        assert_ne!(skeleton("bob"), skeleton("b0c"));
+    }
+}
@@ -0,0 +1,159 @@
+use std::time::{Duration, Instant};
+
+/// How fast something may happen on average, and how much of it may happen at once.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Limit {
+    /// 0 means unlimited.
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+impl Limit {
+    pub fn per_minute(count: u32, burst: u32) -> Self {
+        Limit { per_second: f64::from(count) / 60.0, burst: f64::from(burst) }
+    }
+
+    pub fn per_second(count: u32, burst: u32) -> Self {
+        Limit { per_second: f64::from(count), burst: f64::from(burst) }
+    }
+}
+
+/// Token bucket: taking something takes tokens, which are added back at the limit's rate,
+/// up to its burst. It starts full.
+#[derive(Debug, Clone)]
+pub struct TokenBucket {
+    limit: Limit,
+    /// Negative while paying off a debt (see take).
+    tokens: f64,
+    updated: Instant,
+}
+
+impl TokenBucket {
+    pub fn new(limit: Limit) -> Self {
+        TokenBucket { limit, tokens: limit.burst, updated: Instant::now() }
+    }
+
+    /// How many tokens there are as of now.
+    fn tokens(&self, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(self.updated).as_secs_f64();
+        (self.tokens + elapsed * self.limit.per_second).min(self.limit.burst)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        self.tokens = self.tokens(now);
+        self.updated = now;
+    }
+
+    /// Takes count tokens if there are that many, and returns whether there were.
+    pub fn try_take(&mut self, count: f64) -> bool {
+        if self.limit.per_second == 0.0 {
+            return true;
+        }
+        self.refill();
+        if self.tokens < count {
+            return false;
+        }
+        self.tokens -= count;
+        true
+    }
+
+    /// Takes count tokens even if there are not that many, for things that have already happened
+    /// (such as bytes having been read). Nothing can be taken until the debt is paid off.
+    pub fn take(&mut self, count: f64) {
+        if self.limit.per_second == 0.0 {
+            return;
+        }
+        self.refill();
+        self.tokens -= count;
+    }
+
+    /// How many whole tokens there are now.
+    pub fn available(&self) -> usize {
+        if self.limit.per_second == 0.0 {
+            return usize::MAX;
+        }
+        self.tokens(Instant::now()).max(0.0) as usize
+    }
+
+    /// Whether the bucket has refilled completely, i.e. nothing has been taken for a while.
+    pub fn is_full(&self) -> bool {
+        self.limit.per_second == 0.0 || self.tokens(Instant::now()) >= self.limit.burst
+    }
+
+    /// How long until there is at least one token; zero if there is one now.
+    pub fn wait(&self) -> Duration {
+        if self.limit.per_second == 0.0 {
+            return Duration::ZERO;
+        }
+        let tokens = self.tokens(Instant::now());
+        if tokens >= 1.0 {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((1.0 - tokens) / self.limit.per_second)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Makes it as if secs more seconds had gone by since the bucket was last refilled.
+    fn age(bucket: &mut TokenBucket, secs: f64) {
+        bucket.updated -= Duration::from_secs_f64(secs);
+    }
+
+    #[test]
+    fn buckets_start_full_and_refill_at_the_rate() {
+        let mut bucket = TokenBucket::new(Limit::per_second(2, 4));
+        assert!(bucket.is_full());
+        for _ in 0..4 {
+            assert!(bucket.try_take(1.0));
+        }
+        assert!(!bucket.try_take(1.0));
+        assert_eq!(bucket.available(), 0);
+        assert!(bucket.wait() > Duration::from_millis(400) && bucket.wait() <= Duration::from_millis(500));
+
+        age(&mut bucket, 1.0);
+        assert_eq!(bucket.available(), 2);
+        assert_eq!(bucket.wait(), Duration::ZERO);
+        assert!(!bucket.is_full());
+        assert!(bucket.try_take(2.0));
+        assert!(!bucket.try_take(1.0));
+    }
+
+    #[test]
+    fn refilling_stops_at_the_burst() {
+        let mut bucket = TokenBucket::new(Limit::per_minute(60, 3));
+        assert!(bucket.try_take(3.0));
+        age(&mut bucket, 100.0);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.available(), 3);
+        assert!(!bucket.try_take(4.0));
+        assert!(bucket.try_take(3.0));
+    }
+
+    #[test]
+    fn debts_are_paid_off_before_anything_more_can_be_taken() {
+        let mut bucket = TokenBucket::new(Limit::per_second(10, 10));
+        bucket.take(15.0);
+        assert_eq!(bucket.available(), 0);
+        assert!(!bucket.try_take(1.0));
+        // 5 tokens owed, and one more to take
+        assert!(bucket.wait() > Duration::from_millis(590) && bucket.wait() <= Duration::from_millis(600));
+        age(&mut bucket, 0.5);
+        assert_eq!(bucket.available(), 0);
+        age(&mut bucket, 0.2);
+        assert!(bucket.try_take(1.0));
+    }
+
+    #[test]
+    fn a_rate_of_0_is_unlimited() {
+        let mut bucket = TokenBucket::new(Limit::per_second(0, 0));
+        assert!(bucket.try_take(1e9));
+        bucket.take(1e9);
+        assert_eq!(bucket.available(), usize::MAX);
+        assert!(bucket.is_full());
+        assert_eq!(bucket.wait(), Duration::ZERO);
+    }
+}
@@ -0,0 +1,134 @@
+use std::borrow::Cow;
+use std::fmt::Write;
+
+/// What to do with characters that could mess with a terminal or mislead whoever reads the text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SanitizeMode {
+    /// Leave text as it is.
+    Off,
+    /// Remove them.
+    Strip,
+    /// Replace each with a visible escape like `\u{1b}`.
+    Escape,
+}
+
+impl std::str::FromStr for SanitizeMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match &*s.to_ascii_lowercase() {
+            "off" => Ok(SanitizeMode::Off),
+            "strip" => Ok(SanitizeMode::Strip),
+            "escape" => Ok(SanitizeMode::Escape),
+            _ => Err(format!("invalid sanitize mode {:?} (expected off, strip or escape)", s)),
+        }
+    }
+}
+
+/// Whether c is a control character (which includes ESC, so every terminal escape sequence),
+/// a bidirectional formatting character (which can make text display in a different order than it is read),
+/// or an invisible or line-breaking character that has no business in a name or a one-line message.
+pub fn is_dangerous(c: char) -> bool {
+    matches!(c,
+        // C0 controls, DEL, C1 controls (including the one-byte CSI)
+        '\u{0}'..='\u{1f}' | '\u{7f}'..='\u{9f}'
+        // bidirectional marks, embeddings, overrides and isolates
+        | '\u{61c}' | '\u{200e}' | '\u{200f}' | '\u{202a}'..='\u{202e}' | '\u{2066}'..='\u{2069}'
+        // zero-width and otherwise invisible characters
+        | '\u{ad}' | '\u{180e}' | '\u{200b}'..='\u{200d}' | '\u{2060}'..='\u{2064}' | '\u{feff}'
+        // line and paragraph separators
+        | '\u{2028}' | '\u{2029}'
+        // interlinear annotations and tags, which hide text
+        | '\u{fff9}'..='\u{fffb}' | '\u{e0000}'..='\u{e007f}'
+    )
+}
+
+/// Applies mode to every dangerous character in text, only allocating if there are any.
+pub fn sanitize(text: &str, mode: SanitizeMode) -> Cow<'_, str> {
+    if mode == SanitizeMode::Off || !text.chars().any(is_dangerous) {
+        return Cow::Borrowed(text);
+    }
+    let mut clean = String::with_capacity(text.len());
+    for c in text.chars() {
+        match mode {
+            _ if !is_dangerous(c) => clean.push(c),
+            SanitizeMode::Escape => write!(clean, "\\u{{{:x}}}", c as u32).unwrap(),
+            SanitizeMode::Strip | SanitizeMode::Off => {},
+        };
+    }
+    Cow::Owned(clean)
+}
+
+/// Like sanitize, but keeps text itself if it is already clean.
+pub fn sanitize_cow(text: Cow<'_, str>, mode: SanitizeMode) -> Cow<'_, str> {
+    match sanitize(&text, mode) {
+        Cow::Owned(clean) => Cow::Owned(clean),
+        Cow::Borrowed(_) => text,
+    }
+}
+
+/// Makes text safe to show in the terminal, visibly.
+pub fn escape(text: &str) -> Cow<'_, str> {
+    sanitize(text, SanitizeMode::Escape)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Every attack must come out with nothing a terminal would act on, in both modes.
+    fn assert_neutralized(attack: &str) {
+        for &mode in &[SanitizeMode::Strip, SanitizeMode::Escape] {
+            let clean = sanitize(attack, mode);
+            assert!(!clean.chars().any(is_dangerous), "{:?} gave {:?} with {:?}", attack, clean, mode);
+        }
+    }
+
+    #[test]
+    fn escape_sequences_are_neutralized() {
+        let attacks = [
+            "\x1b[2J\x1b[H",                       // clear the screen
+            "\x1b[31mred\x1b[0m",                  // colours
+            "\x1b]0;pwned\x07",                    // set the window title
+            "\x1b]8;;http://evil.example/\x1b\\click\x1b]8;;\x1b\\", // hyperlink
+            "\x1bP+q544e\x1b\\",                   // DCS query, whose answer is typed into the terminal
+            "\u{9b}6n",                            // C1 CSI: cursor position report
+            "\x1b[6n",                             // same, 7-bit
+            "innocent\rmalicious",                 // overwrite the line
+            "safe\x08\x08\x08\x08evil",            // backspace over it
+            "two\nlines",
+        ];
+        for attack in &attacks {
+            assert_neutralized(attack);
+        }
+        assert_eq!(sanitize("\x1b[31mred", SanitizeMode::Strip), "[31mred");
+        assert_eq!(sanitize("\x1b[31mred", SanitizeMode::Escape), "\\u{1b}[31mred");
+    }
+
+    #[test]
+    fn bidi_and_invisible_characters_are_neutralized() {
+        let attacks = [
+            "\u{202e}txt.exe",                     // right-to-left override: shows as "exe.txt"
+            "admin\u{2066}\u{2069}",               // isolates
+            "\u{200f}\u{200e}\u{61c}",             // marks
+            "ad\u{200b}min",                       // zero-width space
+            "ad\u{2060}min\u{feff}",               // word joiner, BOM
+            "hidden\u{e0041}\u{e0042}tag",         // tag characters
+            "line\u{2028}break",
+        ];
+        for attack in &attacks {
+            assert_neutralized(attack);
+        }
+        assert_eq!(sanitize("ad\u{200b}min", SanitizeMode::Strip), "admin");
+        assert_eq!(sanitize("\u{202e}abc", SanitizeMode::Escape), "\\u{202e}abc");
+    }
+
+    #[test]
+    fn ordinary_text_is_untouched() {
+        for text in &["hello, world", "naïve café", "日本語", "emoji 🎉", "tab-free \\u{1b} already escaped", ""] {
+            assert!(matches!(sanitize(text, SanitizeMode::Escape), Cow::Borrowed(t) if t == *text));
+            assert!(matches!(sanitize(text, SanitizeMode::Strip), Cow::Borrowed(t) if t == *text));
+        }
+        assert_eq!(sanitize("\x1b[2J", SanitizeMode::Off), "\x1b[2J");
+    }
+}
@@ -1,146 +1,1285 @@
+//! The chat server: everything but reading its settings, so that it can also be run from tests or other programs.
+
 use std::net::*;
-use std::io;
-use std::collections::HashMap;
-use std::sync::{Mutex, Arc};
-use std::time::Duration;
+use std::io::{self, prelude::*};
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::UnixStream;
+use std::time::{Duration, Instant};
+use std::sync::Arc;
+use std::path::PathBuf;
+use std::thread::JoinHandle;
+use libc::{POLLIN, POLLOUT, POLLHUP, POLLERR};
 
-mod util;
+use crate::log::Level;
 use crate::util::*;
-
-mod messages;
 use crate::messages::*;
+use crate::sanitize::*;
+use crate::tls::*;
+use crate::history::*;
+use crate::names::*;
+use crate::accounts::*;
+use crate::rate_limit::*;
+
+pub use crate::names::NamePolicy;
+pub use crate::rate_limit::Limit;
+
+/// Longest frame (not counting the length prefix) a client may send.
+const DEFAULT_MAX_FRAME_LEN: u32 = 64 * 1024;
+/// How many of the most recent chat messages in each room are kept in memory for HistoryRequests.
+const HISTORY_BUFFER_LEN: usize = 1_000;
+/// How many of the most recent chat messages (in every room) are read back from the history log on starting.
+const HISTORY_LOAD_LEN: usize = 10_000;
+/// How many chat messages a client with the history capability is sent on joining a room.
+const HISTORY_REPLAY_LEN: u32 = 50;
+/// Most chat messages sent in reply to one HistoryRequest.
+const MAX_HISTORY_PAGE: u32 = 200;
+/// How long a client with the keepalive capability may be quiet before it is pinged.
+const DEFAULT_PING_INTERVAL: Duration = Duration::from_secs(30);
+/// How long a pinged client has to send something before it is disconnected.
+const DEFAULT_PING_TIMEOUT: Duration = Duration::from_secs(30);
+/// How long to stop accepting connections after failing to (e.g. for running out of file descriptors),
+/// rather than failing again straight away.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+/// Most logins and registrations waiting for their passwords to be hashed, from all clients together;
+/// any more are denied as rate limited.
+const MAX_PENDING_PASSWORDS: usize = 32;
+
+struct Client {
+    name: String,
+    /// Non-blocking; only read when poll says it is readable (or it has buffered input),
+    /// only written when poll says it is writable.
+    stream: Stream,
+    /// Protocol version in use with this client; BASE_PROTOCOL_VERSION until it sends a Hello.
+    version: u16,
+    capabilities: Capabilities,
+    /// Chat messages and notices about this client only go to clients in the same room.
+    room: String,
+    /// Its limit is the longest frame this client may send, advertised in the Welcome.
+    decoder: FrameDecoder,
+    /// Frames waiting for the socket to become writable.
+    encoder: FrameEncoder,
+    /// Set when the client is to be disconnected once everything queued has been written.
+    /// Nothing more is read from or queued for a closing client.
+    closing: bool,
+    /// When the client last sent a complete message (or connected).
+    last_heard: Instant,
+    /// When the client was sent a Ping that it has not answered yet (by sending anything).
+    ping_sent: Option<Instant>,
+    /// The registered name the client logged in to (or registered), which it may take back whenever it likes.
+    account: Option<String>,
+    /// Set while the password of a login or registration by the client is being hashed.
+    /// It may not start another one until that one is done.
+    password_pending: bool,
+    /// Chat and direct messages.
+    chat_limit: TokenBucket,
+    /// Name changes, registrations and logins.
+    name_limit: TokenBucket,
+    /// Bytes read from the client.
+    byte_limit: TokenBucket,
+    /// How many messages in a row were rejected for exceeding a rate limit.
+    strikes: u32,
+    /// Whether the client exceeded the byte rate limit (and has not stayed under it for long enough since).
+    throttled: bool,
+}
+
+impl Client {
+    fn new(name: String, stream: Stream, config: &ServerConfig) -> Self {
+        Client {
+            name,
+            stream,
+            version: BASE_PROTOCOL_VERSION,
+            capabilities: Capabilities::NONE,
+            room: DEFAULT_ROOM.into(),
+            decoder: FrameDecoder::new(config.max_frame_len),
+            encoder: FrameEncoder::new(),
+            closing: false,
+            last_heard: Instant::now(),
+            ping_sent: None,
+            account: None,
+            password_pending: false,
+            chat_limit: TokenBucket::new(config.rate_limits.chat),
+            name_limit: TokenBucket::new(config.rate_limits.names),
+            byte_limit: TokenBucket::new(config.rate_limits.bytes),
+            strikes: 0,
+            throttled: false,
+        }
+    }
 
-fn new_name_validity(clients: &HashMap<SocketAddr, (String, TcpStream)>, addr: SocketAddr, new_name: &str) -> Result<(), u8> {
-    if new_name.len() == 0 {
-        return Err(0);
+    /// Whether the client should be read from now.
+    fn wants_read(&self) -> bool {
+        !self.closing && self.byte_limit.wait() == Duration::ZERO
     }
-    for (other_addr, (other_name, _)) in clients.iter() {
-        if &addr != other_addr && other_name == new_name {
-            return Err(1);
+
+    /// Whether there is anything waiting for the socket to become writable.
+    fn has_output(&self) -> bool {
+        !self.encoder.is_empty() || self.stream.wants_write()
+    }
+
+    /// When the keepalive next needs attention for this client: sending a ping, or giving up on one.
+    /// None if it does not have the keepalive capability.
+    fn keepalive_deadline(&self, ping_interval: Duration, ping_timeout: Duration) -> Option<Instant> {
+        if self.closing || !self.capabilities.contains(Capabilities::KEEPALIVE) {
+            return None;
         }
+        Some(match self.ping_sent {
+            Some(ping_sent) => ping_sent + ping_timeout,
+            None => self.last_heard + ping_interval,
+        })
     }
-    return Ok(());
 }
 
-fn main() -> io::Result<()> {
-    let ip_and_maybe_port: (IpAddr, Option<u16>) = get_user_input(
-        io::stdout().lock(),
-        io::stdin().lock(),
-        "Server IP: ",
-        "Invalid IP.\n",
-        |s| -> Option<(IpAddr, Option<u16>)> {
-            let s = s.trim();
-            match s.parse::<SocketAddr>() {
-                Ok(socket) => Some((socket.ip(), Some(socket.port()))),
-                Err(_) => match s.parse() {
-                    Ok(ip) => Some((ip, None)),
-                    Err(_) => None,
-                },
+/// How fast each client may send things.
+#[derive(Debug, Clone)]
+pub struct RateLimits {
+    /// Chat and direct messages; ones over the limit are rejected.
+    pub chat: Limit,
+    /// Name changes, registrations and logins; ones over the limit are denied.
+    pub names: Limit,
+    /// Registrations and logins from each IP address (whichever client they come from),
+    /// since their passwords are slow to hash; ones over the limit are denied.
+    pub passwords: Limit,
+    /// Everything the client sends; once over the limit, it is not read from until it is back under.
+    pub bytes: Limit,
+    /// How many messages in a row may be rejected for exceeding a limit before the client is disconnected
+    /// (0 for never).
+    pub max_strikes: u32,
+}
+
+impl Default for RateLimits {
+    fn default() -> Self {
+        RateLimits {
+            chat: Limit::per_minute(60, 10),
+            names: Limit::per_minute(6, 3),
+            passwords: Limit::per_minute(10, 5),
+            bytes: Limit::per_second(32 * 1024, 256 * 1024),
+            max_strikes: 20,
+        }
+    }
+}
+
+/// Tunables for a Server.
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Frame length limit given to new clients.
+    pub max_frame_len: u32,
+    /// Clients with the keepalive capability are pinged after being quiet for ping_interval,
+    /// and disconnected if they then stay quiet for ping_timeout.
+    pub ping_interval: Duration,
+    pub ping_timeout: Duration,
+    pub name_policy: NamePolicy,
+    /// What is done to control characters etc. in chat and direct messages before they are passed on.
+    pub sanitize: SanitizeMode,
+    /// Accounts whose clients may kick, ban and mute others.
+    pub operators: Vec<String>,
+    pub rate_limits: RateLimits,
+    /// Certificate and key to serve TLS with; without them, connections are not encrypted.
+    pub tls: Option<Arc<rustls::ServerConfig>>,
+    /// Directory to keep the history log in, if there is to be one.
+    pub history_dir: Option<PathBuf>,
+    /// File to keep registered names in, if clients may register them.
+    pub accounts_file: Option<PathBuf>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_frame_len: DEFAULT_MAX_FRAME_LEN,
+            ping_interval: DEFAULT_PING_INTERVAL,
+            ping_timeout: DEFAULT_PING_TIMEOUT,
+            name_policy: NamePolicy::default(),
+            sanitize: SanitizeMode::Escape,
+            operators: vec![],
+            rate_limits: RateLimits::default(),
+            tls: None,
+            history_dir: None,
+            accounts_file: None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Token {
+    Listener,
+    Client(SocketAddr),
+    /// The other end is held by a ServerHandle, which writes to it to stop the server.
+    Shutdown,
+    /// Passwords have been hashed.
+    Passwords,
+}
+
+/// Serves clients on a non-blocking listener, all on one thread: see run, or spawn to run it on a thread of its own.
+pub struct Server {
+    listener: TcpListener,
+    clients: HashMap<SocketAddr, Client>,
+    config: ServerConfig,
+    /// Id for the next relayed chat message.
+    next_message_id: u64,
+    /// Where relayed chat messages and system events are recorded, if anywhere.
+    history: Option<HistoryLog>,
+    /// The last HISTORY_BUFFER_LEN chat messages in each room, oldest first.
+    recent: HashMap<String, VecDeque<Record>>,
+    /// Registered names, if accounts are enabled.
+    accounts: Option<AccountStore>,
+    /// Hashes passwords for registrations and logins, if accounts are enabled.
+    passwords: Option<PasswordHasher<SocketAddr>>,
+    /// Registrations and logins from each IP address, for RateLimits::passwords.
+    /// Buckets are dropped once full again, so this only has addresses that tried recently.
+    password_limits: HashMap<IpAddr, TokenBucket>,
+    /// Skeletons of banned names, and IP addresses that may not connect, with when the bans end.
+    /// Bans only last until the server restarts.
+    banned_names: HashMap<String, Instant>,
+    banned_ips: HashMap<IpAddr, Instant>,
+    /// Skeletons of muted names and accounts, with when the mutes end. Like bans, mutes are kept by name,
+    /// so that they outlast reconnecting, and only last until the server restarts.
+    muted_names: HashMap<String, Instant>,
+    /// Set after failing to accept a connection, to when to try again.
+    accept_paused_until: Option<Instant>,
+    /// Readable once the server is to stop, if it was started with spawn.
+    shutdown: Option<UnixStream>,
+    shutdown_requested: bool,
+}
+
+impl Server {
+    /// Serves clients connecting to listener, opening the history log and account file the config names, if any.
+    pub fn new(listener: TcpListener, config: ServerConfig) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        let history = match &config.history_dir {
+            Some(dir) => Some(HistoryLog::open(dir, DEFAULT_MAX_SEGMENT_LEN)?),
+            None => None,
+        };
+        let accounts = match &config.accounts_file {
+            Some(path) => Some(AccountStore::open(path)?),
+            None => None,
+        };
+        let passwords = match accounts {
+            Some(_) => Some(PasswordHasher::new()?),
+            None => None,
+        };
+        let mut recent: HashMap<String, VecDeque<Record>> = HashMap::new();
+        if let Some(history) = &history {
+            for record in history.recent_messages(HISTORY_LOAD_LEN)? {
+                buffer_recent(&mut recent, record);
             }
         }
-    )?;
-    let ip = ip_and_maybe_port.0;
-    let port: u16 = match ip_and_maybe_port.1 {
-        Some(port) => port,
-        None => get_user_input(
-            io::stdout().lock(),
-            io::stdin().lock(),
-            "Server port: ",
-            "Invalid port.\n",
-            |s| s.trim().parse().ok()
-        )?,
-    };
-
-    let server_addr: SocketAddr = (ip, port).into();
-    let listener = TcpListener::bind(server_addr)?;
-
-    let clients_: Arc<Mutex<HashMap<SocketAddr, (String, TcpStream)>>> = Arc::new(Mutex::new(HashMap::new()));
-
-    let clients__ = Arc::clone(&clients_);
-    let _connection_handler_thread = std::thread::spawn(move || -> io::Result<()> {
-        loop {
-            let (mut stream, addr) = listener.accept()?;
-            let mut clients = clients__.lock().unwrap();
-            let name = format!("{}", addr);
-            let msg = Message::NameAssignment((&name).into());
-            send_msg(&mut stream, &msg.to_bytes())?;
-            let joined_msg = Message::ChatMessage(format!("{} joined", name).into());
-            let joined_msg_bytes = joined_msg.to_bytes();
-            // send "{name} joined" message to all other clients
-            for (_, stream) in clients.values_mut() {
-                send_msg(stream, &joined_msg_bytes)?;
+        Ok(Server {
+            listener,
+            clients: HashMap::new(),
+            config,
+            next_message_id: history.as_ref().map_or(0, HistoryLog::next_message_id),
+            history,
+            recent,
+            accounts,
+            passwords,
+            password_limits: HashMap::new(),
+            banned_names: HashMap::new(),
+            banned_ips: HashMap::new(),
+            muted_names: HashMap::new(),
+            accept_paused_until: None,
+            shutdown: None,
+            shutdown_requested: false,
+        })
+    }
+
+    /// Listens on addr. Port 0 picks any free port; see local_addr.
+    pub fn bind(addr: SocketAddr, config: ServerConfig) -> io::Result<Self> {
+        Server::new(TcpListener::bind(addr)?, config)
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Serves clients until stopped through the ServerHandle (if started with spawn) or an error occurs.
+    pub fn run(&mut self) -> io::Result<()> {
+        while !self.shutdown_requested {
+            self.poll_once()?;
+        }
+        self.disconnect_all();
+        Ok(())
+    }
+
+    /// Runs the server on a thread of its own, until the returned handle stops it.
+    pub fn spawn(mut self) -> io::Result<ServerHandle> {
+        let addr = self.local_addr()?;
+        let (shutdown, shutdown_rx) = UnixStream::pair()?;
+        self.shutdown = Some(shutdown_rx);
+        let thread = std::thread::Builder::new()
+            .name(format!("server {}", addr))
+            .spawn(move || self.run())?;
+        Ok(ServerHandle { addr, shutdown, thread: Some(thread) })
+    }
+
+    /// Says goodbye to every client (as far as their sockets take it without waiting) and closes their connections.
+    fn disconnect_all(&mut self) {
+        log!(Level::Info, "Shutting down; disconnecting {} clients", self.clients.len());
+        for (_, mut client) in self.clients.drain() {
+            client.encoder.push_message(&Message::Disconnect);
+            let _ = client.encoder.write_to(&mut client.stream);
+            client.stream.close();
+        }
+    }
+
+    /// Makes everything recorded in the history log since the last call durable:
+    /// one fsync per pass of the event loop, however many messages it recorded.
+    fn sync_history(&mut self) {
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.sync() {
+                log!(Level::Error, "Failed to write to history log: {}", e);
             }
-            clients.insert(addr, (name, stream));
-        }
-    });
-
-    loop {
-        let mut clients = clients_.lock().unwrap();
-        match poll_in(clients.iter_mut().map(|(addr, (name, stream))| ((addr, name), stream)), 0)? {
-            Some(((addr, name), stream)) => {
-                let msg = recv_msg(stream)?;
-                let src_addr = *addr; // clients's .iter_mut() borrow should end here if name is not used?
-                use Message::*;
-                match Message::from_bytes(&msg[..]) {
-                    Some(Disconnect) => {
-                        let addr = *addr;
-                        let (name, _stream) = clients.remove(&addr).unwrap();
-                        let msg = ChatMessage(format!("{} disconnected", name).into());
-                        let msg_bytes = msg.to_bytes();
-                        for (_, stream) in clients.values_mut() {
-                            send_msg(stream, &msg_bytes)?;
-                        }
+        }
+    }
+
+    /// Appends to the history log, if there is one, and keeps chat messages for HistoryRequests.
+    /// Failing to write to the log is logged, but does not stop the message from being delivered.
+    fn record(&mut self, timestamp: u64, room: &str, message: Message<'static>) {
+        let record = Record { timestamp, room: room.into(), message };
+        if let Some(history) = &mut self.history {
+            if let Err(e) = history.append(&record) {
+                log!(Level::Error, "Failed to write to history log: {}", e);
+            }
+        }
+        buffer_recent(&mut self.recent, record);
+    }
+
+    /// The last count (at most MAX_HISTORY_PAGE) chat messages in room with ids below before_id, oldest first.
+    fn history_batch(&self, room: &str, before_id: u64, count: u32) -> Message<'static> {
+        let count = count.min(MAX_HISTORY_PAGE) as usize;
+        let empty = VecDeque::new();
+        let recent = self.recent.get(room).unwrap_or(&empty);
+        // ids only ever increase, so each room's buffer is sorted by them
+        let end = recent.partition_point(|record| message_id(record) < before_id);
+        let start = end.saturating_sub(count);
+        let messages = recent.range(start..end).filter_map(|record| match &record.message {
+            Message::StampedChatMessage { id, timestamp, sender, text } => {
+                Some(HistoryMessage { id: *id, timestamp: *timestamp, sender: sender.clone(), text: text.clone() })
+            },
+            _ => None,
+        }).collect();
+        Message::HistoryBatch { room: room.to_owned().into(), messages, more: start > 0 }
+    }
+
+    /// Whether addr is still connected and not on its way out.
+    fn is_active(&self, addr: SocketAddr) -> bool {
+        matches!(self.clients.get(&addr), Some(client) if !client.closing)
+    }
+
+    /// Queues a message for every client that is not closing and for which filter returns true.
+    fn broadcast_where(&mut self, msg: &Message, mut filter: impl FnMut(SocketAddr, &Client) -> bool) {
+        let msg_bytes = msg.to_bytes();
+        for (addr, client) in self.clients.iter_mut() {
+            if !client.closing && filter(*addr, client) {
+                client.encoder.push(&msg_bytes);
+            }
+        }
+    }
+
+    /// Queues a message for every client in `room` except `except`.
+    fn broadcast_room(&mut self, room: &str, msg: &Message, except: Option<SocketAddr>) {
+        self.broadcast_where(msg, |addr, client| Some(addr) != except && client.room == room);
+    }
+
+    /// Tells everyone who cares that a client joined, left or was renamed.
+    /// Clients with the roster or system events capabilities get the event (wherever they are),
+    /// and the other clients in `room` except `except` get the notice as a chat message.
+    /// The event is also recorded in the history log.
+    fn announce(&mut self, room: &str, notice: String, event: &Message, except: Option<SocketAddr>) {
+        self.record(unix_millis(), room, event.clone().into_owned());
+        self.broadcast_where(&Message::ChatMessage(notice.into()), |addr, client| {
+            Some(addr) != except && client.room == room && !client.capabilities.contains(Capabilities::SYSTEM_EVENTS)
+        });
+        self.broadcast_where(event, |_, client| {
+            client.capabilities.contains(Capabilities::ROSTER) || client.capabilities.contains(Capabilities::SYSTEM_EVENTS)
+        });
+    }
+
+    fn room_name_validity(room: &str) -> Result<(), u8> {
+        if room.is_empty() || room.len() > 64 || room.chars().any(|c| c.is_whitespace() || is_dangerous(c)) {
+            return Err(0);
+        }
+        Ok(())
+    }
+
+    /// Moves a client to another room, telling the members of both rooms,
+    /// and sending the client the room's recent messages if it has the history capability.
+    fn move_to_room(&mut self, addr: SocketAddr, room: String) {
+        let client = self.clients.get_mut(&addr).unwrap();
+        client.encoder.push_message(&Message::RoomJoined((&room).into()));
+        if client.room == room {
+            return;
+        }
+        let old_room = std::mem::replace(&mut client.room, room);
+        let name = client.name.clone();
+        let room = client.room.clone();
+        if client.capabilities.contains(Capabilities::HISTORY) {
+            let batch = self.history_batch(&room, u64::MAX, HISTORY_REPLAY_LEN);
+            self.clients.get_mut(&addr).unwrap().encoder.push_message(&batch);
+        }
+        self.broadcast_room(&old_room, &Message::ChatMessage(format!("{} left {}", name, old_room).into()), None);
+        self.broadcast_room(&room, &Message::ChatMessage(format!("{} joined {}", name, room).into()), Some(addr));
+    }
+
+    fn room_list(&self) -> Vec<(&str, u32)> {
+        let mut rooms: BTreeMap<&str, u32> = BTreeMap::new();
+        for client in self.clients.values() {
+            *rooms.entry(&client.room).or_insert(0) += 1;
+        }
+        rooms.into_iter().collect()
+    }
+
+    fn new_name_validity(&self, addr: SocketAddr, new_name: &str) -> Result<(), NameDenial> {
+        if new_name.is_empty() {
+            return Err(NameDenial::Empty);
+        }
+        self.config.name_policy.check(new_name)?;
+        // names that only differ in case or look-alike characters count as the same
+        let new_skeleton = skeleton(new_name);
+        if self.is_banned_name(new_name) {
+            return Err(NameDenial::Banned);
+        }
+        let registered = matches!(&self.accounts, Some(accounts) if accounts.is_registered(new_name));
+        let own_account = matches!(&self.clients[&addr].account, Some(account) if skeleton(account) == new_skeleton);
+        if registered && !own_account {
+            return Err(NameDenial::Registered);
+        }
+        for (other_addr, other) in self.clients.iter() {
+            if &addr != other_addr && skeleton(&other.name) == new_skeleton {
+                return Err(NameDenial::AlreadyExists);
+            }
+        }
+        Ok(())
+    }
+
+    /// Starts registering the client's current name with password, unless it is clear already that it cannot be.
+    /// The client is answered once the password is hashed (see finish_register).
+    fn register(&mut self, addr: SocketAddr, password: &str) -> Result<(), AccountDenial> {
+        let name = self.clients[&addr].name.clone();
+        let accounts = self.accounts.as_ref().ok_or(AccountDenial::Other)?;
+        // this also rules out the names the server assigns
+        if self.config.name_policy.check(&name).is_err() {
+            return Err(AccountDenial::InvalidName);
+        }
+        if accounts.is_registered(&name) {
+            return Err(AccountDenial::AlreadyRegistered);
+        }
+        if password.chars().count() < MIN_PASSWORD_LEN {
+            return Err(AccountDenial::PasswordTooShort);
+        }
+        self.hash_password(addr, PasswordJob::Hash { name, password: password.into() })
+    }
+
+    /// Registers the account that was made for the client, if it still can be.
+    fn finish_register(&mut self, addr: SocketAddr, account: io::Result<Account>) -> Result<(), AccountDenial> {
+        let account = account.map_err(|e| {
+            log!(Level::Error, "Failed to hash a password: {}", e);
+            AccountDenial::Other
+        })?;
+        let accounts = self.accounts.as_mut().ok_or(AccountDenial::Other)?;
+        // things may have changed while the password was being hashed
+        if accounts.is_registered(account.name()) {
+            return Err(AccountDenial::AlreadyRegistered);
+        }
+        let client = self.clients.get_mut(&addr).unwrap();
+        if client.name != account.name() {
+            return Err(AccountDenial::InvalidName);
+        }
+        let name = account.name().to_owned();
+        if let Err(e) = accounts.add(account) {
+            log!(Level::Error, "Failed to save accounts: {}", e);
+            return Err(AccountDenial::Other);
+        }
+        log!(Level::Info, "{} registered {}", addr, name);
+        client.account = Some(name);
+        Ok(())
+    }
+
+    /// Starts checking a login; the client is answered once the password is hashed (see finish_login).
+    fn start_login(&mut self, addr: SocketAddr, name: &str, password: &str) -> Result<(), AccountDenial> {
+        let accounts = self.accounts.as_ref().ok_or(AccountDenial::Other)?;
+        let account = accounts.get(name).cloned();
+        if account.is_none() {
+            log!(Level::Info, "{} failed to log in as {}: no such account", addr, name);
+        }
+        self.hash_password(addr, PasswordJob::Check(account, password.into()))
+    }
+
+    /// Logs the client in to account if its password was right, and the account was not banned meanwhile.
+    fn finish_login(&mut self, addr: SocketAddr, account: Option<String>) -> Result<(), AccountDenial> {
+        match account {
+            Some(account) if self.is_banned_name(&account) => Err(AccountDenial::Banned),
+            Some(account) => {
+                self.log_in(addr, account);
+                Ok(())
+            },
+            None => {
+                log!(Level::Info, "{} failed to log in", addr);
+                Err(AccountDenial::InvalidCredentials)
+            },
+        }
+    }
+
+    /// Has the password for a registration or login hashed, unless the client is already waiting for one,
+    /// or its IP address has tried too many passwords lately, or too many are waiting already.
+    fn hash_password(&mut self, addr: SocketAddr, job: PasswordJob) -> Result<(), AccountDenial> {
+        let passwords = self.passwords.as_mut().ok_or(AccountDenial::Other)?;
+        let client = self.clients.get_mut(&addr).unwrap();
+        if client.password_pending || passwords.pending() >= MAX_PENDING_PASSWORDS {
+            return Err(AccountDenial::RateLimited);
+        }
+        let limit = self.config.rate_limits.passwords;
+        self.password_limits.retain(|_, bucket| !bucket.is_full());
+        if !self.password_limits.entry(addr.ip()).or_insert_with(|| TokenBucket::new(limit)).try_take(1.0) {
+            log!(Level::Info, "Rate limiting {} ({}): too many passwords from {}", addr, client.name, addr.ip());
+            return Err(AccountDenial::RateLimited);
+        }
+        client.password_pending = true;
+        passwords.send(addr, job);
+        Ok(())
+    }
+
+    /// Answers the registrations and logins whose passwords have been hashed.
+    fn finish_passwords(&mut self) {
+        let results = match &mut self.passwords {
+            Some(passwords) => passwords.results(),
+            None => return,
+        };
+        for (addr, result) in results {
+            // the client may have left meanwhile (and another connected from the same address)
+            match self.clients.get_mut(&addr) {
+                Some(client) if client.password_pending && !client.closing => client.password_pending = false,
+                _ => continue,
+            }
+            let reply = match result {
+                PasswordResult::Hashed(account) => match self.finish_register(addr, account) {
+                    Ok(()) => Message::RegisterApproval,
+                    Err(reason) => Message::RegisterDenial(reason),
+                },
+                PasswordResult::Checked(account) => match self.finish_login(addr, account) {
+                    // log_in sent the approval
+                    Ok(()) => continue,
+                    Err(reason) => Message::LoginDenial(reason),
+                },
+            };
+            self.clients.get_mut(&addr).unwrap().encoder.push_message(&reply);
+        }
+    }
+
+    /// Gives a client that logged in its account's name, disconnecting any other client logged in to the same account
+    /// (most likely an earlier connection of the same user, that the server has not noticed is gone yet).
+    fn log_in(&mut self, addr: SocketAddr, account: String) {
+        log!(Level::Info, "{} logged in as {}", addr, account);
+        let account_skeleton = skeleton(&account);
+        let others: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(&other_addr, other)| {
+                other_addr != addr && (skeleton(&other.name) == account_skeleton || other.account.as_deref() == Some(&account))
+            })
+            .map(|(&other_addr, _)| other_addr)
+            .collect();
+        for other_addr in others {
+            log!(Level::Info, "Disconnecting {}: {} logged in elsewhere", other_addr, account);
+            self.drop_client(other_addr, LEAVE_REMOVED);
+        }
+        self.carry_mute(addr, &account);
+        let client = self.clients.get_mut(&addr).unwrap();
+        client.account = Some(account.clone());
+        client.encoder.push_message(&Message::LoginApproval((&account).into()));
+        if client.name != account {
+            let old_name = std::mem::replace(&mut client.name, account.clone());
+            let notice = format!("{} is now known as {}", old_name, account);
+            let room = client.room.clone();
+            let event = Message::UserRenamed { room: (&room).into(), old_name: old_name.into(), new_name: account.into() };
+            self.announce(&room, notice, &event, Some(addr));
+        }
+    }
+
+    fn is_banned_name(&self, name: &str) -> bool {
+        matches!(self.banned_names.get(&skeleton(name)), Some(&until) if until > Instant::now())
+    }
+
+    fn is_banned_ip(&self, ip: IpAddr) -> bool {
+        matches!(self.banned_ips.get(&ip), Some(&until) if until > Instant::now())
+    }
+
+    fn is_operator(&self, addr: SocketAddr) -> bool {
+        match &self.clients[&addr].account {
+            Some(account) => self.config.operators.iter().any(|operator| skeleton(operator) == skeleton(account)),
+            None => false,
+        }
+    }
+
+    /// The client with name (or a look-alike; only one client can have it), if it is not on its way out.
+    fn find_client(&self, name: &str) -> Option<SocketAddr> {
+        let name_skeleton = skeleton(name);
+        self.clients.iter()
+            .find(|(_, client)| !client.closing && skeleton(&client.name) == name_skeleton)
+            .map(|(&addr, _)| addr)
+    }
+
+    /// Tells a client why it is being removed, and disconnects it once that has been sent.
+    fn remove_client(&mut self, addr: SocketAddr, notice: String) {
+        let client = self.clients.get_mut(&addr).unwrap();
+        client.encoder.push_message(&Message::ChatMessage(notice.into()));
+        client.closing = true;
+    }
+
+    /// Carries out an operator's ModerationRequest, and announces it to everyone.
+    fn moderate(&mut self, addr: SocketAddr, action: ModerationAction, target: &str, duration_secs: u32, reason: &str) -> Result<(), ModerationError> {
+        if !self.is_operator(addr) {
+            return Err(ModerationError::NotOperator);
+        }
+        let operator = self.clients[&addr].name.clone();
+        let now = Instant::now();
+        let until = now + Duration::from_secs(duration_secs.into());
+        let duration = format_duration(duration_secs);
+        let because = if reason.is_empty() { String::new() } else { format!(": {}", reason) };
+        // whoever was removed or muted already got their own notice (and clients being removed are skipped anyway)
+        let mut except = None;
+        let notice = match action {
+            ModerationAction::Kick => {
+                let target_addr = self.find_client(target).ok_or(ModerationError::NoSuchUser)?;
+                self.remove_client(target_addr, format!("You were kicked by {}{}", operator, because));
+                format!("{} kicked {}{}", operator, target, because)
+            },
+            ModerationAction::Ban => {
+                let banned: Vec<SocketAddr> = match target.parse::<IpAddr>() {
+                    Ok(ip) => {
+                        self.banned_ips.retain(|_, &mut until| until > now);
+                        self.banned_ips.insert(ip, until);
+                        self.clients.iter().filter(|(addr, client)| addr.ip() == ip && !client.closing).map(|(&addr, _)| addr).collect()
                     },
-                    Some(ChatMessage(s)) => {
-                        let msg = ChatMessage(format!("{}: {}", name, s).into());
-                        let msg_bytes = msg.to_bytes();
-                        for (dst_addr, (_, stream)) in clients.iter_mut() {
-                            if &src_addr != dst_addr {
-                                send_msg(stream, &msg_bytes)?;
-                            }
-                        }
+                    Err(_) if target.is_empty() => return Err(ModerationError::NoSuchUser),
+                    Err(_) => {
+                        self.banned_names.retain(|_, &mut until| until > now);
+                        self.banned_names.insert(skeleton(target), until);
+                        self.find_client(target).into_iter().collect()
                     },
-                    Some(NameChangeRequest(new_name)) => {
-                        let src_addr = *addr;
-                        match new_name_validity(&*clients, src_addr, &new_name) {
-                            Ok(()) => {
-                                let mut new_name: String = new_name.into();
-                                let (name, stream) = clients.get_mut(&src_addr).unwrap();
-                                std::mem::swap(name, &mut new_name);
-                                let old_name = new_name;
-                                send_msg(stream, &NameChangeApproval.to_bytes())?;
-                                let msg = ChatMessage(format!("{} is now known as {}", old_name, name).into());
-                                let msg_bytes = msg.to_bytes();
-                                for (dst_addr, (_, stream)) in clients.iter_mut() {
-                                    if dst_addr != &src_addr {
-                                        send_msg(stream, &msg_bytes)?;
-                                    }
-                                }
-                            },
-                            Err(reason) => {
-                                let (_, stream) = clients.get_mut(&src_addr).unwrap();
-                                send_msg(stream, &NameChangeDenial(reason).to_bytes())?;
-                            },
-                        }
+                };
+                if duration_secs == 0 {
+                    format!("{} lifted the ban on {}", operator, target)
+                } else {
+                    for banned_addr in banned {
+                        self.remove_client(banned_addr, format!("You were banned by {} for {}{}", operator, duration, because));
+                    }
+                    format!("{} banned {} for {}{}", operator, target, duration, because)
+                }
+            },
+            ModerationAction::Mute if duration_secs == 0 => {
+                // whoever had the name may have gone, or be logged in to a muted account under another name
+                let (name, account) = match self.find_client(target) {
+                    Some(target_addr) => {
+                        let client = &self.clients[&target_addr];
+                        (client.name.clone(), client.account.clone())
                     },
-                    _ => todo!(),
+                    None => (target.to_owned(), None),
                 };
+                let mut unmuted = false;
+                for muted in std::iter::once(&name).chain(&account) {
+                    unmuted |= self.muted_names.remove(&skeleton(muted)).is_some();
+                }
+                if !unmuted {
+                    return Err(ModerationError::NoSuchUser);
+                }
+                format!("{} unmuted {}", operator, name)
             },
-            None => {
-                drop(clients); // explicitly unlock and sleep so other thread can lock the mutex
-                std::thread::sleep(Duration::from_millis(50));
+            ModerationAction::Mute => {
+                let target_addr = self.find_client(target).ok_or(ModerationError::NoSuchUser)?;
+                self.muted_names.retain(|_, &mut until| until > now);
+                let client = self.clients.get_mut(&target_addr).unwrap();
+                for muted in std::iter::once(&client.name).chain(&client.account) {
+                    self.muted_names.insert(skeleton(muted), until);
+                }
+                except = Some(target_addr);
+                client.encoder.push_message(&Message::ChatMessage(format!("You were muted by {} for {}{}", operator, duration, because).into()));
+                format!("{} muted {} for {}{}", operator, client.name, duration, because)
+            },
+            ModerationAction::Unknown(_) => return Err(ModerationError::Other),
+        };
+        log!(Level::Info, "{}: {}", addr, notice);
+        self.broadcast_where(&Message::ChatMessage(notice.into()), |addr, _| Some(addr) != except);
+        Ok(())
+    }
+
+    /// Takes a token from one of a client's buckets, returning whether there was one. If there was not,
+    /// the client is sent rejection, and gets a strike; it is disconnected once it has too many in a row.
+    fn within_rate_limit(&mut self, addr: SocketAddr, bucket: impl FnOnce(&mut Client) -> &mut TokenBucket, what: &str, rejection: Message) -> bool {
+        let max_strikes = self.config.rate_limits.max_strikes;
+        let client = self.clients.get_mut(&addr).unwrap();
+        if bucket(client).try_take(1.0) {
+            client.strikes = 0;
+            return true;
+        }
+        client.encoder.push_message(&rejection);
+        client.strikes += 1;
+        if client.strikes == 1 {
+            log!(Level::Info, "Rate limiting {} ({}): too many {}", addr, client.name, what);
+        }
+        if max_strikes > 0 && client.strikes >= max_strikes {
+            log!(Level::Info, "Disconnecting {} ({}): kept exceeding rate limits", addr, client.name);
+            self.remove_client(addr, "You kept sending faster than the server allows, so it is disconnecting you.".into());
+        }
+        false
+    }
+
+    /// Until when the client is muted, if an operator muted its name or account and the mute has not run out yet.
+    fn muted_until(&self, addr: SocketAddr) -> Option<Instant> {
+        let client = &self.clients[&addr];
+        let now = Instant::now();
+        std::iter::once(&client.name).chain(&client.account)
+            .filter_map(|name| self.muted_names.get(&skeleton(name)).copied())
+            .filter(|&until| until > now)
+            .max()
+    }
+
+    fn is_muted(&self, addr: SocketAddr) -> bool {
+        self.muted_until(addr).is_some()
+    }
+
+    /// Mutes new_name for as long as the client is muted, so that a muted client cannot get out of it
+    /// by changing its name or logging in.
+    fn carry_mute(&mut self, addr: SocketAddr, new_name: &str) {
+        if let Some(until) = self.muted_until(addr) {
+            let entry = self.muted_names.entry(skeleton(new_name)).or_insert(until);
+            *entry = (*entry).max(until);
+        }
+    }
+
+    /// Removes a client and tells everyone else it left,
+    /// whether it sent a Disconnect or its connection just went away.
+    /// reason is one of the LEAVE_* constants.
+    fn drop_client(&mut self, addr: SocketAddr, reason: u8) {
+        if let Some(Client { name, room, mut stream, .. }) = self.clients.remove(&addr) {
+            stream.close();
+            log!(Level::Info, "{} ({}) left", addr, name);
+            let notice = format!("{} disconnected", name);
+            let event = Message::UserLeft { room: (&room).into(), name: name.into(), reason };
+            self.announce(&room, notice, &event, None);
+        }
+    }
+
+    /// Accepts every pending connection.
+    fn accept(&mut self) {
+        loop {
+            let (sock, addr) = match self.listener.accept() {
+                Ok(conn) => conn,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                // the peer gave up before we got to it; not our problem
+                Err(e) if e.kind() == io::ErrorKind::ConnectionAborted => continue,
+                // e.g. out of file descriptors or memory: the listener would stay readable, so give
+                // the clients we have a chance to free some up before trying again
+                Err(e) => {
+                    log!(Level::Error, "Failed to accept a connection: {}", e);
+                    self.accept_paused_until = Some(Instant::now() + ACCEPT_BACKOFF);
+                    return;
+                },
+            };
+            if let Err(e) = sock.set_nonblocking(true) {
+                log!(Level::Warn, "Failed to set up connection from {}: {}", addr, e);
+                continue;
+            }
+            if self.is_banned_ip(addr.ip()) {
+                log!(Level::Info, "Refusing connection from {}: banned", addr);
+                // best effort: a fresh socket takes this much without blocking, and it is closed either way
+                // (over TLS, nothing can be said before the handshake, so it is just closed)
+                if self.config.tls.is_none() {
+                    let mut encoder = FrameEncoder::new();
+                    encoder.push_message(&Message::NameAssignment(addr.to_string().into()));
+                    encoder.push_message(&Message::ChatMessage("You are banned from this server.".into()));
+                    encoder.push_message(&Message::Disconnect);
+                    let _ = encoder.write_to(&mut &sock);
+                }
+                continue;
+            }
+            let stream = match &self.config.tls {
+                Some(tls) => match Stream::server(sock, tls) {
+                    Ok(stream) => stream,
+                    Err(e) => {
+                        log!(Level::Warn, "Failed to set up connection from {}: {}", addr, e);
+                        continue;
+                    },
+                },
+                None => Stream::Plain(sock),
+            };
+            log!(Level::Info, "Accepted connection from {}", addr);
+            let name = format!("{}", addr);
+            let mut client = Client::new(name, stream, &self.config);
+            client.encoder.push_message(&Message::NameAssignment((&client.name).into()));
+            // send "{name} joined" message to all other clients in the room
+            let notice = format!("{} joined", client.name);
+            let event = Message::UserJoined { room: (&client.room).into(), name: (&client.name).into() };
+            self.announce(&client.room, notice, &event, None);
+            self.clients.insert(addr, client);
+        }
+    }
+
+    fn handle_message(&mut self, src_addr: SocketAddr, msg: Message) {
+        use Message::*;
+        match msg {
+            Hello { version, capabilities } => {
+                let (version, mut capabilities) = negotiate(version, capabilities);
+                // operators are accounts, so without accounts nobody can moderate either
+                if self.accounts.is_none() {
+                    let without = Capabilities::ACCOUNTS | Capabilities::MODERATION;
+                    capabilities = capabilities & Capabilities::from_bits(!without.bits());
+                }
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.version = version;
+                client.capabilities = capabilities;
+                let max_frame_len = client.decoder.max_len();
+                client.encoder.push_message(&Welcome { version, capabilities, max_frame_len });
+                if capabilities.contains(Capabilities::ROOMS) {
+                    client.encoder.push_message(&RoomJoined((&client.room).into()));
+                }
+                if capabilities.contains(Capabilities::HISTORY) {
+                    let batch = self.history_batch(&self.clients[&src_addr].room, u64::MAX, HISTORY_REPLAY_LEN);
+                    self.clients.get_mut(&src_addr).unwrap().encoder.push_message(&batch);
+                }
+            },
+            Disconnect => {
+                self.drop_client(src_addr, LEAVE_DISCONNECTED);
+            },
+            Ping(value) => {
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&Pong(value));
+            },
+            // receiving anything at all already counts as an answer to our ping
+            Pong(_) => {},
+            ChatMessage(_) if self.is_muted(src_addr) => {
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&ChatMessageError(ChatError::Muted));
+            },
+            ChatMessage(text) => {
+                if !self.within_rate_limit(src_addr, |client| &mut client.chat_limit, "chat messages", ChatMessageError(ChatError::RateLimited)) {
+                    return;
+                }
+                let text = sanitize_cow(text, self.config.sanitize);
+                let Client { name, room, .. } = &self.clients[&src_addr];
+                let (name, room) = (name.clone(), room.clone());
+                let id = self.next_message_id;
+                self.next_message_id += 1;
+                let timestamp = unix_millis();
+                // each client gets the most detailed form it understands
+                let v1_msg = ChatMessage(format!("{}: {}", name, text).into());
+                self.broadcast_where(&v1_msg, |addr, client| {
+                    addr != src_addr && client.room == room
+                        && !client.capabilities.contains(Capabilities::RELAYED_MESSAGES)
+                        && !client.capabilities.contains(Capabilities::TIMESTAMPS)
+                });
+                let relayed_msg = RelayedChatMessage { sender: (&name).into(), text: (&*text).into() };
+                self.broadcast_where(&relayed_msg, |addr, client| {
+                    addr != src_addr && client.room == room
+                        && client.capabilities.contains(Capabilities::RELAYED_MESSAGES)
+                        && !client.capabilities.contains(Capabilities::TIMESTAMPS)
+                });
+                let stamped_msg = StampedChatMessage { id, timestamp, sender: name.into(), text };
+                self.broadcast_where(&stamped_msg, |addr, client| {
+                    addr != src_addr && client.room == room && client.capabilities.contains(Capabilities::TIMESTAMPS)
+                });
+                self.record(timestamp, &room, stamped_msg.into_owned());
+            },
+            HistoryRequest { before_id, count } => {
+                let batch = self.history_batch(&self.clients[&src_addr].room, before_id, count);
+                self.clients.get_mut(&src_addr).unwrap().encoder.push_message(&batch);
+            },
+            RosterRequest => {
+                let names = self.clients.values().map(|client| client.name.clone().into()).collect();
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&Roster(names));
+            },
+            DirectMessage { peer: recipient, .. } if self.is_muted(src_addr) => {
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&DirectMessageError { reason: DIRECT_MUTED, recipient });
+            },
+            DirectMessage { peer: recipient, text } => {
+                let rejection = DirectMessageError { reason: DIRECT_RATE_LIMITED, recipient: recipient.clone() };
+                if !self.within_rate_limit(src_addr, |client| &mut client.chat_limit, "direct messages", rejection) {
+                    return;
+                }
+                let text = sanitize_cow(text, self.config.sanitize);
+                let sender = self.clients[&src_addr].name.clone();
+                match self.find_client(&recipient).and_then(|addr| self.clients.get_mut(&addr)) {
+                    Some(client) if client.capabilities.contains(Capabilities::DIRECT_MESSAGES) => {
+                        client.encoder.push_message(&DirectMessage { peer: sender.into(), text });
+                    },
+                    Some(client) => {
+                        // the best an old client can do is show it as a chat message
+                        client.encoder.push_message(&ChatMessage(format!("(private) {}: {}", sender, text).into()));
+                    },
+                    None => {
+                        let client = self.clients.get_mut(&src_addr).unwrap();
+                        client.encoder.push_message(&DirectMessageError { reason: DIRECT_NO_SUCH_USER, recipient });
+                    },
+                }
+            },
+            RoomJoinRequest(room) => {
+                match Self::room_name_validity(&room) {
+                    Ok(()) => self.move_to_room(src_addr, room.into()),
+                    Err(reason) => {
+                        let client = self.clients.get_mut(&src_addr).unwrap();
+                        client.encoder.push_message(&RoomJoinDenial(reason));
+                    },
+                }
+            },
+            RoomPartRequest => {
+                self.move_to_room(src_addr, DEFAULT_ROOM.into());
+            },
+            RoomListRequest => {
+                let rooms = self.room_list().into_iter().map(|(room, members)| (room.to_owned().into(), members)).collect();
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&RoomList(rooms));
+            },
+            NameChangeRequest(new_name) => {
+                if !self.within_rate_limit(src_addr, |client| &mut client.name_limit, "name changes", NameChangeDenial(NameDenial::RateLimited)) {
+                    return;
+                }
+                match self.new_name_validity(src_addr, &new_name) {
+                    Ok(()) => {
+                        self.carry_mute(src_addr, &new_name);
+                        let client = self.clients.get_mut(&src_addr).unwrap();
+                        let old_name = std::mem::replace(&mut client.name, new_name.into());
+                        client.encoder.push_message(&NameChangeApproval);
+                        let notice = format!("{} is now known as {}", old_name, client.name);
+                        let room = client.room.clone();
+                        let new_name = client.name.clone();
+                        // the event also goes to the client itself, so its roster stays consistent
+                        let event = UserRenamed { room: (&room).into(), old_name: old_name.into(), new_name: new_name.into() };
+                        self.announce(&room, notice, &event, Some(src_addr));
+                    },
+                    Err(reason) => {
+                        let client = self.clients.get_mut(&src_addr).unwrap();
+                        client.encoder.push_message(&NameChangeDenial(reason));
+                    },
+                }
+            },
+            RegisterRequest { password } => {
+                if !self.within_rate_limit(src_addr, |client| &mut client.name_limit, "registrations", RegisterDenial(AccountDenial::RateLimited)) {
+                    return;
+                }
+                if let Err(reason) = self.register(src_addr, &password) {
+                    let client = self.clients.get_mut(&src_addr).unwrap();
+                    client.encoder.push_message(&RegisterDenial(reason));
+                }
+            },
+            LoginRequest { name, password } => {
+                if !self.within_rate_limit(src_addr, |client| &mut client.name_limit, "logins", LoginDenial(AccountDenial::RateLimited)) {
+                    return;
+                }
+                if let Err(reason) = self.start_login(src_addr, &name, &password) {
+                    let client = self.clients.get_mut(&src_addr).unwrap();
+                    client.encoder.push_message(&LoginDenial(reason));
+                }
+            },
+            ModerationRequest { action, target, duration_secs, reason } => {
+                let reason = sanitize(&reason, self.config.sanitize);
+                if let Err(error) = self.moderate(src_addr, action, &target, duration_secs, &reason) {
+                    let client = self.clients.get_mut(&src_addr).unwrap();
+                    client.encoder.push_message(&ModerationDenial(error));
+                }
+            },
+            // server -> client messages; a well-behaved client never sends these
+            NameAssignment(_) | Welcome { .. } | Roster(_) | UserJoined { .. } | UserLeft { .. } | UserRenamed { .. }
+            | ChatMessageError(_) | DirectMessageError { .. } | RelayedChatMessage { .. } | StampedChatMessage { .. } | HistoryBatch { .. } | RoomJoined(_) | RoomJoinDenial(_) | RoomList(_)
+            | NameChangeApproval | NameChangeDenial(_) | RegisterApproval | RegisterDenial(_) | LoginApproval(_) | LoginDenial(_)
+            | ModerationDenial(_) => {
+                log!(Level::Warn, "Ignoring unexpected message type {} from {}", msg.message_type(), src_addr);
+            },
+        };
+    }
+
+    /// Handles a frame that Message::from_bytes could not parse.
+    fn handle_invalid_frame(&mut self, src_addr: SocketAddr, frame: &[u8]) {
+        match frame.first() {
+            Some(&64) => {
+                // chat message whose text is not valid UTF-8
+                let client = self.clients.get_mut(&src_addr).unwrap();
+                client.encoder.push_message(&Message::ChatMessageError(ChatError::InvalidUtf8));
+            },
+            Some(&message_type) => log!(Level::Warn, "Ignoring invalid message of type {} from {}", message_type, src_addr),
+            None => log!(Level::Warn, "Ignoring empty message from {}", src_addr),
+        }
+    }
+
+    /// Starts disconnecting a client that sent a frame over its limit.
+    /// Oversized chat messages are answered with an error first, so the user knows what happened.
+    fn reject_oversized_frame(&mut self, addr: SocketAddr, err: FrameTooLarge) {
+        let client = self.clients.get_mut(&addr).unwrap();
+        log!(Level::Info, "Disconnecting {}: {}", addr, err);
+        if err.message_type == 64 {
+            client.encoder.push_message(&Message::ChatMessageError(ChatError::TooLong));
+        }
+        client.closing = true;
+    }
+
+    /// Reads whatever `addr` has sent and handles every complete message.
+    /// If the connection was closed or broke, the client is dropped after handling
+    /// whatever it managed to send before that.
+    fn service_readable(&mut self, addr: SocketAddr) {
+        let client = self.clients.get_mut(&addr).unwrap();
+        let buffered = client.decoder.buffered_len();
+        // no more than a longest possible frame at a time (as with read_from), even without a byte rate limit,
+        // so that a client sending faster than it can be read cannot keep the server reading
+        let frame_room = (client.decoder.max_len() as usize + 4).saturating_sub(buffered);
+        let result = client.decoder.read_at_most(&mut client.stream, client.byte_limit.available().min(frame_room));
+        client.byte_limit.take((client.decoder.buffered_len() - buffered) as f64);
+        if !client.wants_read() && !client.throttled {
+            log!(Level::Info, "Throttling {} ({}): over the byte rate limit", addr, client.name);
+            client.throttled = true;
+        } else if client.byte_limit.is_full() {
+            client.throttled = false;
+        }
+        // stop if disconnected by an earlier message
+        while let Some(client) = self.clients.get_mut(&addr).filter(|client| !client.closing) {
+            match client.decoder.next_message() {
+                Ok(Some(msg)) => {
+                    client.last_heard = Instant::now();
+                    client.ping_sent = None;
+                    self.handle_message(addr, msg);
+                },
+                Ok(None) => break,
+                Err(DecodeError::Invalid(frame)) => self.handle_invalid_frame(addr, &frame),
+                Err(DecodeError::TooLarge(err)) => self.reject_oversized_frame(addr, err),
+            };
+        }
+        if let Err(e) = result {
+            if self.clients.contains_key(&addr) {
+                if e.kind() != io::ErrorKind::UnexpectedEof {
+                    log!(Level::Info, "Error reading from {}: {}", addr, e);
+                }
+                self.drop_client(addr, LEAVE_CONNECTION_LOST);
+            }
+        }
+    }
+
+    /// Pings clients that have been quiet for too long, and drops those that did not answer in time.
+    fn check_keepalives(&mut self) {
+        let now = Instant::now();
+        let (ping_interval, ping_timeout) = (self.config.ping_interval, self.config.ping_timeout);
+        let mut timed_out = vec![];
+        for (addr, client) in self.clients.iter_mut() {
+            match client.keepalive_deadline(ping_interval, ping_timeout) {
+                Some(deadline) if deadline <= now => {},
+                _ => continue,
+            };
+            if client.ping_sent.is_some() {
+                timed_out.push(*addr);
+            } else {
+                client.encoder.push_message(&Message::Ping(unix_millis()));
+                client.ping_sent = Some(now);
+            }
+        }
+        for addr in timed_out {
+            log!(Level::Info, "Disconnecting {}: no response to ping", addr);
+            self.drop_client(addr, LEAVE_CONNECTION_LOST);
+        }
+    }
+
+    /// Milliseconds until check_keepalives next has something to do, or a throttled client
+    /// can be read from again, or connections can be accepted again, or -1 if never (for poll).
+    fn poll_timeout(&self) -> i32 {
+        let now = Instant::now();
+        let keepalives = self.clients.values()
+            .filter_map(|client| client.keepalive_deadline(self.config.ping_interval, self.config.ping_timeout));
+        let throttles = self.clients.values()
+            .filter(|client| !client.closing)
+            .map(|client| client.byte_limit.wait())
+            .filter(|&wait| wait > Duration::ZERO)
+            .map(|wait| now + wait);
+        let next = keepalives.chain(throttles).chain(self.accept_paused_until).min();
+        match next {
+            Some(deadline) => {
+                // round up, so that the deadline has passed when poll returns
+                let wait = deadline.saturating_duration_since(Instant::now()) + Duration::from_nanos(999_999);
+                wait.as_millis().min(i32::MAX as u128) as i32
             },
+            None => -1,
+        }
+    }
+
+    /// Waits until the listener or some client is ready (or a keepalive is due, or a client is no longer throttled),
+    /// and services everything that is.
+    pub fn poll_once(&mut self) -> io::Result<()> {
+        // input that was decrypted but left unread (e.g. because of the byte rate limit) is no longer
+        // in the socket, so poll would not say it is there
+        let buffered: Vec<SocketAddr> = self.clients.iter()
+            .filter(|(_, client)| client.wants_read() && client.stream.has_buffered_input())
+            .map(|(addr, _)| *addr)
+            .collect();
+        if self.accept_paused_until.is_some_and(|until| Instant::now() >= until) {
+            self.accept_paused_until = None;
+        }
+        let listener = match self.accept_paused_until {
+            Some(_) => None,
+            None => Some((Token::Listener, self.listener.as_raw_fd(), POLLIN)),
         };
+        let shutdown = self.shutdown.iter().map(|shutdown| (Token::Shutdown, shutdown.as_raw_fd(), POLLIN));
+        let passwords = self.passwords.iter().map(|passwords| (Token::Passwords, passwords.as_raw_fd(), POLLIN));
+        let clients = self.clients.iter().map(|(addr, client)| {
+            // throttled clients are left unread, so TCP slows them down
+            let events = match (client.closing, !client.has_output(), client.wants_read()) {
+                (false, true, true) => POLLIN,
+                (false, true, false) => 0,
+                (false, false, true) => POLLIN | POLLOUT,
+                (false, false, false) => POLLOUT,
+                (true, _, _) => POLLOUT,
+            };
+            (Token::Client(*addr), client.stream.as_raw_fd(), events)
+        });
+        let timeout = if buffered.is_empty() { self.poll_timeout() } else { 0 };
+        let ready = poll_events(listener.into_iter().chain(shutdown).chain(passwords).chain(clients), timeout)?;
+
+        for (token, revents) in ready {
+            match token {
+                Token::Listener => self.accept(),
+                Token::Shutdown => self.shutdown_requested = true,
+                Token::Passwords => self.finish_passwords(),
+                Token::Client(addr) => {
+                    if revents & POLLOUT != 0 {
+                        if let Some(client) = self.clients.get_mut(&addr) {
+                            match client.encoder.write_to(&mut client.stream) {
+                                Err(e) => {
+                                    log!(Level::Info, "Error writing to {}: {}", addr, e);
+                                    self.drop_client(addr, LEAVE_CONNECTION_LOST);
+                                },
+                                Ok(()) if client.closing && !client.has_output() => self.drop_client(addr, LEAVE_REMOVED),
+                                Ok(()) => {},
+                            }
+                        }
+                    }
+                    // POLLHUP and POLLERR also go through a read, so that anything sent before
+                    // the hangup is still handled, and so the read reports the actual error.
+                    if revents & (POLLIN | POLLHUP | POLLERR) != 0 && self.is_active(addr) {
+                        self.service_readable(addr);
+                    }
+                    if revents & (POLLHUP | POLLERR) != 0 && self.clients.contains_key(&addr) {
+                        self.drop_client(addr, LEAVE_CONNECTION_LOST);
+                    }
+                },
+            };
+        }
+        for addr in buffered {
+            if self.is_active(addr) {
+                self.service_readable(addr);
+            }
+        }
+        self.check_keepalives();
+        self.sync_history();
+        Ok(())
+    }
+}
 
-//        let mut x = [0u8];
-//        stream.read_exact(&mut x)?;
-//        let mut s = String::new();
-//        Read::take(&stream, x[0] as u64).read_to_string(&mut s)?;
-//
-//        println!("Received \"{}\" from {:?}.", s, addr);
-//
-//        let s = format!("Hello, {:?}.", addr);
-//        stream.write(&[s.len() as u8])?;
-//        stream.write(s.as_bytes())?;
+/// Keeps a chat message in its room's buffer of recent ones, dropping the oldest if it is full.
+/// Anything else is not kept.
+fn buffer_recent(recent: &mut HashMap<String, VecDeque<Record>>, record: Record) {
+    if let Message::StampedChatMessage { .. } = record.message {
+        let buffer = recent.entry(record.room.clone()).or_default();
+        if buffer.len() == HISTORY_BUFFER_LEN {
+            buffer.pop_front();
+        }
+        buffer.push_back(record);
+    }
+}
+
+fn message_id(record: &Record) -> u64 {
+    match record.message {
+        Message::StampedChatMessage { id, .. } => id,
+        _ => 0,
+    }
+}
+
+/// A server running on a thread of its own (see Server::spawn). Dropping it stops the server too.
+pub struct ServerHandle {
+    addr: SocketAddr,
+    shutdown: UnixStream,
+    thread: Option<JoinHandle<io::Result<()>>>,
+}
+
+impl ServerHandle {
+    /// The address the server is listening on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// Disconnects every client and stops the server, waiting for it to finish.
+    /// Returns the error that stopped the server earlier, if one did.
+    pub fn shutdown(mut self) -> io::Result<()> {
+        self.stop()
+    }
+
+    fn stop(&mut self) -> io::Result<()> {
+        let thread = match self.thread.take() {
+            Some(thread) => thread,
+            None => return Ok(()),
+        };
+        // this fails if the server already stopped, which is just as good
+        let _ = self.shutdown.write_all(&[0]);
+        thread.join().unwrap_or_else(|_| Err(io::Error::other("the server thread panicked")))
+    }
+}
+
+impl Drop for ServerHandle {
+    fn drop(&mut self) {
+        let _ = self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn server() -> Server {
+        Server::bind("127.0.0.1:0".parse().unwrap(), ServerConfig::default()).unwrap()
+    }
+
+    fn chat(server: &mut Server, room: &str, id: u64) {
+        let message = Message::StampedChatMessage { id, timestamp: id, sender: "alice".into(), text: id.to_string().into() };
+        server.record(id, room, message);
+    }
+
+    /// The ids in a HistoryBatch, and whether it says there are more.
+    fn ids(batch: Message) -> (Vec<u64>, bool) {
+        match batch {
+            Message::HistoryBatch { messages, more, .. } => (messages.iter().map(|message| message.id).collect(), more),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn history_pages_back_through_one_room() {
+        let mut server = server();
+        for id in 0..10 {
+            chat(&mut server, if id % 2 == 0 { "lobby" } else { "den" }, id);
+        }
+        assert_eq!(ids(server.history_batch("lobby", u64::MAX, 50)), (vec![0, 2, 4, 6, 8], false));
+        assert_eq!(ids(server.history_batch("den", u64::MAX, 2)), (vec![7, 9], true));
+        // before_id itself is not included
+        assert_eq!(ids(server.history_batch("den", 7, 2)), (vec![3, 5], true));
+        assert_eq!(ids(server.history_batch("den", 6, 2)), (vec![3, 5], true));
+        // exactly the rest is not "more"
+        assert_eq!(ids(server.history_batch("den", 5, 2)), (vec![1, 3], false));
+        assert_eq!(ids(server.history_batch("den", 1, 2)), (vec![], false));
+        assert_eq!(ids(server.history_batch("nowhere", u64::MAX, 2)), (vec![], false));
+    }
+
+    #[test]
+    fn history_pages_are_limited() {
+        let mut server = server();
+        for id in 0..MAX_HISTORY_PAGE as u64 + 10 {
+            chat(&mut server, "lobby", id);
+        }
+        let (page, more) = ids(server.history_batch("lobby", u64::MAX, u32::MAX));
+        assert_eq!(page.len(), MAX_HISTORY_PAGE as usize);
+        assert_eq!(page[0], 10);
+        assert!(more);
+    }
+
+    #[test]
+    fn busy_rooms_do_not_push_out_quiet_ones() {
+        let mut server = server();
+        chat(&mut server, "den", 0);
+        for id in 1..=HISTORY_BUFFER_LEN as u64 + 1 {
+            chat(&mut server, "lobby", id);
+        }
+        assert_eq!(ids(server.history_batch("den", u64::MAX, 10)), (vec![0], false));
+        assert_eq!(server.recent["lobby"].len(), HISTORY_BUFFER_LEN);
+        assert_eq!(ids(server.history_batch("lobby", 3, 10)), (vec![2], false));
     }
 }
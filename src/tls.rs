@@ -0,0 +1,391 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, prelude::*};
+use std::net::{SocketAddr, TcpStream};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::crypto::{verify_tls12_signature, verify_tls13_signature, WebPkiSupportedAlgorithms};
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{DigitallySignedStruct, SignatureScheme};
+
+/// A connection to the other side, encrypted or not.
+/// Reading and writing work the same either way, blocking or not, so the framing does not need to care.
+pub enum Stream {
+    Plain(TcpStream),
+    Tls(Box<TlsStream>),
+}
+
+impl Stream {
+    /// Starts the server side of a TLS connection; the handshake happens as the stream is read from and written to.
+    pub fn server(sock: TcpStream, config: &Arc<rustls::ServerConfig>) -> io::Result<Self> {
+        let conn = rustls::ServerConnection::new(Arc::clone(config)).map_err(tls_error)?;
+        Ok(Stream::Tls(Box::new(TlsStream::new(conn.into(), sock))))
+    }
+
+    pub fn tcp(&self) -> &TcpStream {
+        match self {
+            Stream::Plain(sock) => sock,
+            Stream::Tls(tls) => &tls.sock,
+        }
+    }
+
+    /// Whether there is encrypted data that the socket did not take yet. Writing (or flushing) sends it.
+    pub fn wants_write(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(tls) => tls.conn.wants_write(),
+        }
+    }
+
+    /// Whether there is data that has been received and decrypted, but not read yet.
+    /// poll does not know about it, since it is no longer in the socket.
+    pub fn has_buffered_input(&self) -> bool {
+        match self {
+            Stream::Plain(_) => false,
+            Stream::Tls(tls) => tls.buffered > 0,
+        }
+    }
+
+    /// Tells the other side that the connection is being closed on purpose, so that it can tell
+    /// this from someone cutting the connection short. Best effort; the stream should be dropped afterwards.
+    pub fn close(&mut self) {
+        if let Stream::Tls(tls) = self {
+            tls.conn.send_close_notify();
+            let _ = tls.write_tls();
+        }
+    }
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.read(buf),
+            Stream::Tls(tls) => tls.read(buf),
+        }
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Stream::Plain(sock) => sock.write(buf),
+            Stream::Tls(tls) => tls.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Stream::Plain(sock) => sock.flush(),
+            Stream::Tls(tls) => tls.flush(),
+        }
+    }
+}
+
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        match self {
+            Stream::Plain(sock) => sock.as_raw_fd(),
+            Stream::Tls(tls) => tls.sock.as_raw_fd(),
+        }
+    }
+}
+
+/// A TLS connection over a socket that may be non-blocking.
+/// Unlike rustls::StreamOwned, this never waits for the socket: whatever it does not take yet
+/// stays buffered (see Stream::wants_write), and reads that need more from it fail with WouldBlock.
+pub struct TlsStream {
+    conn: rustls::Connection,
+    sock: TcpStream,
+    /// How many bytes have been decrypted but not read yet.
+    buffered: usize,
+}
+
+impl TlsStream {
+    fn new(conn: rustls::Connection, sock: TcpStream) -> Self {
+        TlsStream { conn, sock, buffered: 0 }
+    }
+
+    /// Writes as much encrypted data as the socket takes.
+    fn write_tls(&mut self) -> io::Result<()> {
+        while self.conn.wants_write() {
+            match self.conn.write_tls(&mut self.sock) {
+                Ok(_) => {},
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
+    }
+
+    fn process_new_packets(&mut self) -> io::Result<()> {
+        match self.conn.process_new_packets() {
+            Ok(state) => {
+                self.buffered = state.plaintext_bytes_to_read();
+                Ok(())
+            },
+            Err(err) => {
+                // get the alert saying what went wrong to the other side, if possible
+                let _ = self.write_tls();
+                Err(tls_error(err))
+            },
+        }
+    }
+}
+
+impl Read for TlsStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            // Ok(0) if the other side closed the connection, UnexpectedEof if the socket just ended
+            match self.conn.reader().read(buf) {
+                Ok(n) => {
+                    self.buffered = self.buffered.saturating_sub(n);
+                    return Ok(n);
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {},
+                Err(e) => return Err(e),
+            };
+            // during the handshake, the other side is waiting for what we have to say
+            self.write_tls()?;
+            self.conn.read_tls(&mut self.sock)?;
+            self.process_new_packets()?;
+            self.write_tls()?;
+        }
+    }
+}
+
+impl Write for TlsStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // buffered until the handshake is done, and up to a limit after that
+        let mut written = self.conn.writer().write(buf)?;
+        self.write_tls()?;
+        if written == 0 && !buf.is_empty() {
+            written = self.conn.writer().write(buf)?;
+            self.write_tls()?;
+        }
+        if written == 0 && !buf.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.write_tls()?;
+        if self.conn.wants_write() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        Ok(())
+    }
+}
+
+/// Errors of our own making (see CertificateChanged) are reported as they are, without rustls' wrapping.
+fn tls_error(err: rustls::Error) -> io::Error {
+    match err {
+        rustls::Error::Other(rustls::OtherError(err)) => io::Error::new(io::ErrorKind::InvalidData, err.to_string()),
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// Reads a certificate chain (leaf first) and its private key, both PEM, to serve TLS with.
+pub fn server_config(cert_path: &Path, key_path: &Path) -> io::Result<Arc<rustls::ServerConfig>> {
+    let certs = CertificateDer::pem_file_iter(cert_path)
+        .and_then(|certs| certs.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| pem_error(cert_path, e))?;
+    if certs.is_empty() {
+        return Err(pem_error(cert_path, rustls::pki_types::pem::Error::NoItemsFound));
+    }
+    let key = PrivateKeyDer::from_pem_file(key_path).map_err(|e| pem_error(key_path, e))?;
+    let config = rustls::ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, format!("{} and {}: {}", cert_path.display(), key_path.display(), e)))?;
+    Ok(Arc::new(config))
+}
+
+fn pem_error(path: &Path, err: rustls::pki_types::pem::Error) -> io::Error {
+    match err {
+        rustls::pki_types::pem::Error::Io(e) => io::Error::new(e.kind(), format!("{}: {}", path.display(), e)),
+        e => io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)),
+    }
+}
+
+/// How the client connects over TLS, and which servers it trusts.
+pub struct ClientTls {
+    config: Arc<rustls::ClientConfig>,
+    server_name: ServerName<'static>,
+    /// Only when trusting on first use.
+    known_hosts: Option<Arc<KnownHosts>>,
+}
+
+impl ClientTls {
+    /// Trusts certificates for server_name issued by the certificate authorities in ca_path (PEM).
+    pub fn with_ca(ca_path: &Path, server_name: ServerName<'static>) -> io::Result<Self> {
+        let mut roots = rustls::RootCertStore::empty();
+        for cert in CertificateDer::pem_file_iter(ca_path).map_err(|e| pem_error(ca_path, e))? {
+            let cert = cert.map_err(|e| pem_error(ca_path, e))?;
+            roots.add(cert).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", ca_path.display(), e)))?;
+        }
+        if roots.is_empty() {
+            return Err(pem_error(ca_path, rustls::pki_types::pem::Error::NoItemsFound));
+        }
+        let config = rustls::ClientConfig::builder().with_root_certificates(roots).with_no_client_auth();
+        Ok(ClientTls { config: Arc::new(config), server_name, known_hosts: None })
+    }
+
+    /// Trusts whatever certificate the server at addr has the first time, and only that certificate after that,
+    /// like SSH does. The fingerprints of trusted certificates are kept in known_hosts_path.
+    pub fn trust_on_first_use(known_hosts_path: PathBuf, addr: SocketAddr) -> Self {
+        let known_hosts = Arc::new(KnownHosts {
+            path: known_hosts_path,
+            host: addr.to_string(),
+            algorithms: rustls::crypto::ring::default_provider().signature_verification_algorithms,
+            newly_trusted: Mutex::new(None),
+        });
+        let config = rustls::ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::clone(&known_hosts) as Arc<dyn ServerCertVerifier>)
+            .with_no_client_auth();
+        ClientTls { config: Arc::new(config), server_name: addr.ip().into(), known_hosts: Some(known_hosts) }
+    }
+
+    /// Starts a TLS connection over sock; the handshake happens as the stream is read from and written to.
+    pub fn connect(&self, sock: TcpStream) -> io::Result<Stream> {
+        let conn = rustls::ClientConnection::new(Arc::clone(&self.config), self.server_name.clone()).map_err(tls_error)?;
+        Ok(Stream::Tls(Box::new(TlsStream::new(conn.into(), sock))))
+    }
+
+    /// The fingerprint of the certificate trusted for the first time since this was last called, if any.
+    pub fn take_newly_trusted(&self) -> Option<String> {
+        self.known_hosts.as_ref()?.newly_trusted.lock().unwrap().take()
+    }
+}
+
+/// Certificate verifier for trusting on first use.
+#[derive(Debug)]
+struct KnownHosts {
+    /// Lines of the form `host fingerprint`.
+    path: PathBuf,
+    /// The address of the server, as it appears in the file.
+    host: String,
+    algorithms: WebPkiSupportedAlgorithms,
+    newly_trusted: Mutex<Option<String>>,
+}
+
+impl KnownHosts {
+    fn add(&self, fingerprint: &str) -> io::Result<()> {
+        if let Some(dir) = self.path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = OpenOptions::new().create(true).append(true).open(&self.path)?;
+        writeln!(file, "{} {}", self.host, fingerprint)
+    }
+}
+
+impl ServerCertVerifier for KnownHosts {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, rustls::Error> {
+        let presented = fingerprint(end_entity);
+        let known_hosts = match fs::read_to_string(&self.path) {
+            Ok(text) => text,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(rustls::Error::General(format!("{}: {}", self.path.display(), e))),
+        };
+        match known_fingerprint(&known_hosts, &self.host) {
+            Some(expected) if expected == presented => {},
+            Some(expected) => {
+                let err = CertificateChanged { host: self.host.clone(), expected: expected.into(), presented, path: self.path.clone() };
+                return Err(rustls::Error::Other(rustls::OtherError(Arc::new(err))));
+            },
+            None => {
+                self.add(&presented).map_err(|e| rustls::Error::General(format!("{}: {}", self.path.display(), e)))?;
+                *self.newly_trusted.lock().unwrap() = Some(presented);
+            },
+        };
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn verify_tls13_signature(&self, message: &[u8], cert: &CertificateDer<'_>, dss: &DigitallySignedStruct) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(message, cert, dss, &self.algorithms)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.algorithms.supported_schemes()
+    }
+}
+
+/// The server has a different certificate than the one trusted on first use.
+#[derive(Debug)]
+struct CertificateChanged {
+    host: String,
+    expected: String,
+    presented: String,
+    path: PathBuf,
+}
+
+impl std::fmt::Display for CertificateChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "the certificate of {} has changed (fingerprint {}, expected {}); \
+             if the server's certificate was replaced on purpose, remove its line from {}",
+            self.host, self.presented, self.expected, self.path.display(),
+        )
+    }
+}
+
+impl std::error::Error for CertificateChanged {}
+
+/// SHA-256 of cert, like `openssl x509 -noout -fingerprint -sha256` shows it.
+fn fingerprint(cert: &[u8]) -> String {
+    let digest = ring::digest::digest(&ring::digest::SHA256, cert);
+    let hex: Vec<String> = digest.as_ref().iter().map(|byte| format!("{:02X}", byte)).collect();
+    hex.join(":")
+}
+
+/// The fingerprint known_hosts has for host, if any. Blank lines and lines starting with # are ignored.
+fn known_fingerprint<'a>(known_hosts: &'a str, host: &str) -> Option<&'a str> {
+    known_hosts.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            let mut fields = line.split_whitespace();
+            Some((fields.next()?, fields.next()?))
+        })
+        .find(|&(known_host, _)| known_host == host)
+        .map(|(_, fingerprint)| fingerprint)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn known_hosts_are_looked_up_by_address() {
+        let known_hosts = "\
+# trusted on first use
+127.0.0.1:7878 AA:BB
+[::1]:7878 CC:DD
+
+10.0.0.1:7878 EE:FF extra
+";
+        assert_eq!(known_fingerprint(known_hosts, "127.0.0.1:7878"), Some("AA:BB"));
+        assert_eq!(known_fingerprint(known_hosts, "[::1]:7878"), Some("CC:DD"));
+        assert_eq!(known_fingerprint(known_hosts, "10.0.0.1:7878"), Some("EE:FF"));
+        assert_eq!(known_fingerprint(known_hosts, "127.0.0.1:7879"), None);
+        assert_eq!(known_fingerprint("", "127.0.0.1:7878"), None);
+        assert_eq!(fingerprint(b"abc"), "BA:78:16:BF:8F:01:CF:EA:41:41:40:DE:5D:AE:22:23:B0:03:61:A3:96:17:7A:9C:B4:10:FF:61:F2:00:15:AD");
+    }
+}
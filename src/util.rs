@@ -1,9 +1,10 @@
 use std::io::{self, prelude::*};
-use std::os::unix::io::AsRawFd;
-use libc::{poll, pollfd, POLLIN};
+use std::os::unix::io::RawFd;
+use libc::{poll, pollfd};
 use std::convert::TryInto;
+use std::collections::VecDeque;
 
-//use crate::messages::Message;
+use crate::messages::Message;
 // TODO: maybe make send_msg and recv_msg use Message? Maybe by having Message keep a cached to_bytes?
 // But then it couldn't really be an enum so maybe don't do that and keep how I have it now
 // where to_bytes is called explicitly
@@ -30,40 +31,73 @@ pub fn get_user_input<T>(
     }
 }
 
+/// Milliseconds since the Unix epoch.
+pub fn unix_millis() -> u64 {
+    let since_epoch = std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+    since_epoch.as_millis().try_into().unwrap()
+}
+
+/// Units durations can be written in, largest first.
+const DURATION_UNITS: [(char, u32); 4] = [('d', 24 * 60 * 60), ('h', 60 * 60), ('m', 60), ('s', 1)];
+
+/// Parses a number of seconds written like "90", "90s", "10m", "2h" or "7d".
+pub fn parse_duration(s: &str) -> Option<u32> {
+    let (number, unit_secs) = match s.char_indices().last()? {
+        (i, unit) if unit.is_ascii_alphabetic() => {
+            let &(_, unit_secs) = DURATION_UNITS.iter().find(|&&(u, _)| u == unit.to_ascii_lowercase())?;
+            (&s[..i], unit_secs)
+        },
+        _ => (s, 1),
+    };
+    number.parse::<u32>().ok()?.checked_mul(unit_secs)
+}
+
+/// Formats a number of seconds in the largest unit that divides it, e.g. "10m" rather than "600s".
+pub fn format_duration(secs: u32) -> String {
+    match DURATION_UNITS.iter().find(|&&(_, unit_secs)| secs.is_multiple_of(unit_secs)) {
+        Some(&(unit, unit_secs)) if secs > 0 => format!("{}{}", secs / unit_secs, unit),
+        _ => format!("{}s", secs),
+    }
+}
+
+/// Waits for the requested events (POLLIN, POLLOUT) on every fd at once.
 /// timeout < 0 -> block forever
 /// timeout == 0 -> return immediately
 /// timeout > 0 -> block for timeout milliseconds
-/// Returns the index of the first Fd that was ready for reading,
-/// or None if none were ready before the timeout, or some errored.
-#[allow(dead_code)] // only used in server
-pub fn poll_in<'a, K, F: AsRawFd + ?Sized>(fds: impl Iterator<Item=(K, &'a mut F)>, timeout: i32) -> io::Result<Option<(K, &'a mut F)>> {
-    let (mut pollfds, refs): (Vec<pollfd>, Vec<(K, &'a mut F)>) = fds.map(
-        |arf| { (
+/// Returns the key and the returned events (which may also include POLLHUP and POLLERR)
+/// of every fd that had any events before the timeout.
+/// Interruption by a signal is reported as no fds being ready.
+pub fn poll_events<K>(fds: impl Iterator<Item=(K, RawFd, i16)>, timeout: i32) -> io::Result<Vec<(K, i16)>> {
+    let (mut pollfds, keys): (Vec<pollfd>, Vec<K>) = fds.map(
+        |(key, fd, events)| { (
             pollfd {
-                fd: arf.1.as_raw_fd(),
-                events: POLLIN,
+                fd,
+                events,
                 revents: 0,
             },
-            arf
+            key
         )}
     ).unzip();
-    
+
     let ret = unsafe {
         poll(pollfds.as_mut_ptr(), pollfds.len().try_into().unwrap(), timeout)
     };
 
-    if ret == 0 {
-        Ok(None)
-    } else if ret > 0 {
-        Ok(pollfds.iter().zip(refs.into_iter()).filter_map(
-            |(pollfd, r)| if pollfd.revents & POLLIN != 0 {
-                Some(r)
+    if ret >= 0 {
+        Ok(pollfds.iter().zip(keys).filter_map(
+            |(pollfd, key)| if pollfd.revents != 0 {
+                Some((key, pollfd.revents))
             } else {
                 None
             }
-        ).next())
+        ).collect())
     } else { // ret < 0
-        Err(io::Error::last_os_error())
+        let err = io::Error::last_os_error();
+        if err.kind() == io::ErrorKind::Interrupted {
+            Ok(vec![])
+        } else {
+            Err(err)
+        }
     }
 }
 
@@ -71,14 +105,393 @@ pub fn send_msg(destination: &mut impl io::Write, msg: &[u8]) -> io::Result<()>
     let len: u32 = msg.len().try_into().unwrap();
     destination.write_all(&len.to_le_bytes())?;
     destination.write_all(msg)?;
-    Ok(())
+    destination.flush()
+}
+
+/// A frame's length prefix was larger than the receiver's limit.
+/// recv_msg returns this wrapped in an io::Error of kind InvalidData
+/// (use io::Error::get_ref and downcast_ref to tell it apart from other errors).
+/// Only the message type byte of the frame is consumed, so the stream cannot be used afterwards.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FrameTooLarge {
+    pub len: u32,
+    pub max_len: u32,
+    /// The first byte of the frame, so that e.g. oversized chat messages can be answered with an error.
+    pub message_type: u8,
 }
 
-pub fn recv_msg(src: &mut impl io::Read) -> io::Result<Vec<u8>> {
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "frame of {} bytes exceeds the limit of {} bytes", self.len, self.max_len)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+impl From<FrameTooLarge> for io::Error {
+    fn from(err: FrameTooLarge) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, err)
+    }
+}
+
+/// Receives one frame, refusing (before allocating anything) frames longer than max_len bytes.
+pub fn recv_msg(src: &mut impl io::Read, max_len: u32) -> io::Result<Vec<u8>> {
     let mut len_buf: [u8; 4] = [0; 4];
     src.read_exact(&mut len_buf[..])?;
     let len: u32 = u32::from_le_bytes(len_buf);
+    if len > max_len {
+        let mut message_type = [0u8];
+        src.read_exact(&mut message_type)?;
+        return Err(FrameTooLarge { len, max_len, message_type: message_type[0] }.into());
+    }
     let mut data = vec![0u8; len.try_into().unwrap()];
     src.read_exact(&mut data[..])?;
     Ok(data)
 }
+
+/// Error from FrameDecoder::next_message.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The decoder cannot continue after this.
+    TooLarge(FrameTooLarge),
+    /// A complete frame that Message::from_bytes could not parse.
+    /// Decoding can continue with the next frame.
+    Invalid(Vec<u8>),
+}
+
+/// Reassembles frames from a byte stream that arrives in arbitrary chunks,
+/// e.g. from a non-blocking socket.
+#[derive(Debug)]
+pub struct FrameDecoder {
+    /// Bytes received that do not yet form a complete frame.
+    buf: Vec<u8>,
+    max_len: u32,
+}
+
+impl FrameDecoder {
+    /// Frames longer than max_len bytes (not counting the length prefix) are refused
+    /// before anything is allocated for them.
+    pub fn new(max_len: u32) -> Self {
+        FrameDecoder {
+            buf: vec![],
+            max_len,
+        }
+    }
+
+    pub fn max_len(&self) -> u32 {
+        self.max_len
+    }
+
+    /// How many bytes have been received that are not part of a frame returned yet.
+    pub fn buffered_len(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Adds bytes received from the stream.
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Pushes what can currently be read from a non-blocking src, but only until a longest possible frame
+    /// (max_len + 4 bytes) is buffered, however fast src is; take frames out before reading again.
+    /// EOF is reported as an error of kind UnexpectedEof, after pushing everything before it.
+    pub fn read_from(&mut self, src: &mut impl Read) -> io::Result<()> {
+        let limit = (self.max_len as usize + 4).saturating_sub(self.buf.len());
+        self.read_at_most(src, limit)
+    }
+
+    /// Like read_from, but stops after pushing (about) limit bytes, even if there is more to read.
+    pub fn read_at_most(&mut self, src: &mut impl Read, mut limit: usize) -> io::Result<()> {
+        let mut buf = [0u8; 4096];
+        while limit > 0 {
+            let len = buf.len().min(limit);
+            match src.read(&mut buf[..len]) {
+                Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+                Ok(n) => {
+                    self.push(&buf[..n]);
+                    limit -= n;
+                },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            };
+        }
+        Ok(())
+    }
+
+    /// Removes and returns the first complete frame, if any.
+    /// An oversized frame is only reported once its message type byte has arrived.
+    pub fn next_frame(&mut self) -> Result<Option<Vec<u8>>, FrameTooLarge> {
+        if self.buf.len() < 4 {
+            return Ok(None);
+        }
+        let len = u32::from_le_bytes(self.buf[..4].try_into().unwrap());
+        if len > self.max_len {
+            return match self.buf.get(4) {
+                Some(&message_type) => Err(FrameTooLarge { len, max_len: self.max_len, message_type }),
+                None => Ok(None),
+            };
+        }
+        let len: usize = len.try_into().unwrap();
+        if self.buf.len() - 4 < len {
+            return Ok(None);
+        }
+        let frame = self.buf[4..4 + len].to_vec();
+        self.buf.drain(..4 + len);
+        Ok(Some(frame))
+    }
+
+    /// Removes and parses the first complete frame, if any.
+    pub fn next_message(&mut self) -> Result<Option<Message<'static>>, DecodeError> {
+        match self.next_frame() {
+            Ok(Some(frame)) => match Message::from_bytes(&frame) {
+                Some(msg) => Ok(Some(msg.into_owned())),
+                None => Err(DecodeError::Invalid(frame)),
+            },
+            Ok(None) => Ok(None),
+            Err(err) => Err(DecodeError::TooLarge(err)),
+        }
+    }
+}
+
+/// Queues frames for a non-blocking stream, keeping track of how much has been written.
+#[derive(Debug, Default)]
+pub struct FrameEncoder {
+    /// Encoded frames (or the rest of a partially written one) not yet written.
+    buf: VecDeque<u8>,
+}
+
+impl FrameEncoder {
+    pub fn new() -> Self {
+        FrameEncoder::default()
+    }
+
+    /// Queues one frame containing msg_bytes.
+    pub fn push(&mut self, msg_bytes: &[u8]) {
+        let len: u32 = msg_bytes.len().try_into().unwrap();
+        self.buf.extend(&len.to_le_bytes());
+        self.buf.extend(msg_bytes);
+    }
+
+    pub fn push_message(&mut self, msg: &Message) {
+        self.push(&msg.to_bytes());
+    }
+
+    /// Whether everything queued has been written.
+    pub fn is_empty(&self) -> bool {
+        self.buf.is_empty()
+    }
+
+    /// Writes as much of the queue as a non-blocking dst currently accepts,
+    /// and flushes dst (which for TLS may still have something to write even once the queue is empty).
+    pub fn write_to(&mut self, dst: &mut impl Write) -> io::Result<()> {
+        while !self.buf.is_empty() {
+            let (front, _) = self.buf.as_slices();
+            match dst.write(front) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => { self.buf.drain(..n); },
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {},
+                Err(e) => return Err(e),
+            };
+        }
+        match dst.flush() {
+            Err(e) if e.kind() != io::ErrorKind::WouldBlock => Err(e),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn frame(msg: &Message) -> Vec<u8> {
+        let mut encoder = FrameEncoder::new();
+        encoder.push_message(msg);
+        encoder.buf.into()
+    }
+
+    #[test]
+    fn frames_are_reassembled_from_any_chunks() {
+        let hello = frame(&Message::ChatMessage("hello".into()));
+        let mut decoder = FrameDecoder::new(64);
+        // the length prefix split, then the payload split
+        for chunk in [&hello[..2], &hello[2..4], &hello[4..6]] {
+            decoder.push(chunk);
+            assert_eq!(decoder.next_message(), Ok(None));
+        }
+        decoder.push(&hello[6..]);
+        assert_eq!(decoder.next_message(), Ok(Some(Message::ChatMessage("hello".into()))));
+        assert_eq!(decoder.next_message(), Ok(None));
+        assert_eq!(decoder.buffered_len(), 0);
+
+        // several frames, and the start of another, in one push
+        let mut bytes = [frame(&Message::ChatMessage("a".into())), frame(&Message::Ping(1)), hello.clone()].concat();
+        bytes.extend(&hello[..3]);
+        decoder.push(&bytes);
+        assert_eq!(decoder.next_message(), Ok(Some(Message::ChatMessage("a".into()))));
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Ping(1))));
+        assert_eq!(decoder.next_message(), Ok(Some(Message::ChatMessage("hello".into()))));
+        assert_eq!(decoder.next_message(), Ok(None));
+        assert_eq!(decoder.buffered_len(), 3);
+    }
+
+    #[test]
+    fn oversized_and_invalid_frames_are_reported() {
+        let mut decoder = FrameDecoder::new(16);
+        // an invalid frame can be skipped
+        decoder.push(&[1, 0, 0, 0, 0xee]);
+        decoder.push(&frame(&Message::Ping(2)));
+        assert_eq!(decoder.next_message(), Err(DecodeError::Invalid(vec![0xee])));
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Ping(2))));
+
+        // an oversized one is refused as soon as its message type is known, without waiting for the rest
+        decoder.push(&17u32.to_le_bytes());
+        assert_eq!(decoder.next_message(), Ok(None));
+        decoder.push(&[64]);
+        let too_large = FrameTooLarge { len: 17, max_len: 16, message_type: 64 };
+        assert_eq!(decoder.next_message(), Err(DecodeError::TooLarge(too_large)));
+    }
+
+    #[test]
+    fn read_from_buffers_at_most_one_frame() {
+        // a length prefix of u32::MAX, and an endless frame after it
+        let mut src = io::repeat(0xff);
+        let mut decoder = FrameDecoder::new(100);
+        decoder.read_from(&mut src).unwrap();
+        assert_eq!(decoder.buffered_len(), 104);
+        decoder.read_from(&mut src).unwrap();
+        assert_eq!(decoder.buffered_len(), 104);
+        assert!(matches!(decoder.next_message(), Err(DecodeError::TooLarge(_))));
+
+        let bytes = [frame(&Message::Ping(3)), frame(&Message::Pong(3))].concat();
+        let mut decoder = FrameDecoder::new(100);
+        assert_eq!(decoder.read_from(&mut &bytes[..]).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Ping(3))));
+        assert_eq!(decoder.next_message(), Ok(Some(Message::Pong(3))));
+    }
+
+    /// Gives out its chunks one read at a time (splitting those bigger than the buffer), then would block.
+    struct ScriptedReader {
+        reads: VecDeque<io::Result<Vec<u8>>>,
+    }
+
+    impl Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.reads.pop_front() {
+                Some(Ok(mut chunk)) => {
+                    let len = chunk.len().min(buf.len());
+                    buf[..len].copy_from_slice(&chunk[..len]);
+                    if len < chunk.len() {
+                        self.reads.push_front(Ok(chunk.split_off(len)));
+                    }
+                    Ok(len)
+                },
+                Some(Err(e)) => Err(e),
+                None => Err(io::ErrorKind::WouldBlock.into()),
+            }
+        }
+    }
+
+    #[test]
+    fn read_at_most_stops_at_the_limit() {
+        let mut decoder = FrameDecoder::new(u32::MAX);
+        decoder.read_at_most(&mut io::empty(), 0).unwrap();
+        assert_eq!(decoder.buffered_len(), 0);
+        // more than one read's worth
+        decoder.read_at_most(&mut io::repeat(1), 10_000).unwrap();
+        assert_eq!(decoder.buffered_len(), 10_000);
+        decoder.read_at_most(&mut io::repeat(1), 1).unwrap();
+        assert_eq!(decoder.buffered_len(), 10_001);
+    }
+
+    #[test]
+    fn read_at_most_stops_when_the_source_would_block() {
+        let mut src = ScriptedReader { reads: VecDeque::from(vec![
+            Ok(vec![1; 3]),
+            Err(io::ErrorKind::Interrupted.into()),
+            Ok(vec![2; 5000]),
+        ]) };
+        let mut decoder = FrameDecoder::new(u32::MAX);
+        decoder.read_at_most(&mut src, 100_000).unwrap();
+        assert_eq!(decoder.buffered_len(), 5003);
+        decoder.read_at_most(&mut src, 100_000).unwrap();
+        assert_eq!(decoder.buffered_len(), 5003);
+    }
+
+    #[test]
+    fn read_at_most_keeps_what_came_before_an_error() {
+        let mut src = ScriptedReader { reads: VecDeque::from(vec![Ok(vec![1; 3]), Err(io::ErrorKind::ConnectionReset.into())]) };
+        let mut decoder = FrameDecoder::new(u32::MAX);
+        assert_eq!(decoder.read_at_most(&mut src, 100).unwrap_err().kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(decoder.buffered_len(), 3);
+
+        let mut decoder = FrameDecoder::new(u32::MAX);
+        assert_eq!(decoder.read_at_most(&mut &[1, 2, 3][..], 100).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(decoder.buffered_len(), 3);
+        // the limit is reached before the end is
+        decoder.read_at_most(&mut &[1, 2, 3][..], 2).unwrap();
+        assert_eq!(decoder.buffered_len(), 5);
+    }
+
+    /// Takes at most a few bytes per write, and only so many before blocking.
+    struct SlowWriter {
+        written: Vec<u8>,
+        per_write: usize,
+        room: usize,
+    }
+
+    impl Write for SlowWriter {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.room == 0 {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            let len = buf.len().min(self.per_write).min(self.room);
+            self.written.extend(&buf[..len]);
+            self.room -= len;
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_to_keeps_what_was_not_written() {
+        let mut encoder = FrameEncoder::new();
+        encoder.push_message(&Message::ChatMessage("hello".into()));
+        encoder.push_message(&Message::Ping(4));
+        let expected = [frame(&Message::ChatMessage("hello".into())), frame(&Message::Ping(4))].concat();
+        let mut dst = SlowWriter { written: vec![], per_write: 3, room: 7 };
+        encoder.write_to(&mut dst).unwrap();
+        assert_eq!(dst.written, expected[..7]);
+        assert!(!encoder.is_empty());
+        dst.room = usize::MAX;
+        encoder.write_to(&mut dst).unwrap();
+        assert_eq!(dst.written, expected);
+        assert!(encoder.is_empty());
+    }
+
+    #[test]
+    fn durations_are_parsed_in_any_unit() {
+        let cases = [("90", 90), ("90s", 90), ("10m", 600), ("10M", 600), ("2h", 7200), ("7d", 7 * 86400), ("0", 0)];
+        for &(text, secs) in &cases {
+            assert_eq!(parse_duration(text), Some(secs), "{}", text);
+        }
+        for &text in &["", "m", "5y", "-1", "1.5h", " 10m", "10m ", "50000d", "4294967296"] {
+            assert_eq!(parse_duration(text), None, "{:?}", text);
+        }
+        assert_eq!(parse_duration("4294967295"), Some(u32::MAX));
+    }
+
+    #[test]
+    fn durations_are_formatted_in_the_largest_unit_that_fits() {
+        let cases = [(0, "0s"), (90, "90s"), (600, "10m"), (3600, "1h"), (3601, "3601s"), (7 * 86400, "7d"), (36 * 3600, "36h")];
+        for &(secs, text) in &cases {
+            assert_eq!(format_duration(secs), text);
+            assert_eq!(parse_duration(text), Some(secs));
+        }
+    }
+}
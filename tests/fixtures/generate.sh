@@ -0,0 +1,21 @@
+#!/bin/sh
+# Regenerates the certificates and keys tests/tls.rs uses. They are only for tests, so they last a century.
+# ca.pem: a certificate authority. server.pem: a certificate for 127.0.0.1 that it issued.
+# other.pem: a self-signed certificate for 127.0.0.1, which the authority did not issue.
+set -e
+cd "$(dirname "$0")"
+days=36500
+
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out ca.key
+openssl req -x509 -new -key ca.key -days $days -subj "/CN=chatapp test CA" \
+    -addext "basicConstraints=critical,CA:TRUE" -addext "keyUsage=critical,keyCertSign,cRLSign" -out ca.pem
+
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out server.key
+openssl req -new -key server.key -subj "/CN=127.0.0.1" -out server.csr
+printf 'basicConstraints=critical,CA:FALSE\nkeyUsage=critical,digitalSignature\nextendedKeyUsage=serverAuth\nsubjectAltName=IP:127.0.0.1\n' > server.ext
+openssl x509 -req -in server.csr -CA ca.pem -CAkey ca.key -CAcreateserial -days $days -extfile server.ext -out server.pem
+rm -f server.csr server.ext ca.srl ca.key
+
+openssl genpkey -algorithm EC -pkeyopt ec_paramgen_curve:P-256 -out other.key
+openssl req -x509 -new -key other.key -days $days -subj "/CN=127.0.0.1" \
+    -addext "basicConstraints=critical,CA:FALSE" -addext "subjectAltName=IP:127.0.0.1" -out other.pem
@@ -0,0 +1,435 @@
+//! Runs a server in-process and checks what scripted clients connected to it see.
+
+use std::io::{self, prelude::*};
+use std::net::TcpStream;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use chatapp::client::{Client, Event};
+use chatapp::messages::*;
+use chatapp::server::{Limit, RateLimits, Server, ServerConfig, ServerHandle};
+
+/// How long to wait for something that should happen right away.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn start_server() -> ServerHandle {
+    start_server_with(ServerConfig::default())
+}
+
+fn start_server_with(config: ServerConfig) -> ServerHandle {
+    chatapp::set_level(chatapp::Level::Off);
+    let server = Server::bind("127.0.0.1:0".parse().unwrap(), config).unwrap();
+    server.spawn().unwrap()
+}
+
+/// A path for a test's files, unique to the test, with nothing there yet.
+fn temp_path(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("chatapp-server-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Connects a client, and waits until the server has welcomed it (so its capabilities are known).
+fn connect(server: &ServerHandle) -> Client {
+    let mut client = Client::connect(server.local_addr(), None, PROTOCOL_VERSION).unwrap();
+    wait_for(&mut client, |msg| matches!(msg, Message::Welcome { .. }));
+    client
+}
+
+/// Waits for the first message that matches, skipping any others.
+fn wait_for(client: &mut Client, mut matches: impl FnMut(&Message) -> bool) -> Message<'static> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match client.wait_event(Some(deadline.saturating_duration_since(Instant::now()))) {
+            Ok(Some(Event::Message(msg))) if matches(&msg) => return msg,
+            Ok(Some(_)) => {},
+            Ok(None) => panic!("{} timed out waiting for a message", client.name()),
+            Err(e) => panic!("{} lost its connection: {}", client.name(), e),
+        };
+    }
+}
+
+/// Waits for the server to close the connection, returning the v1 chat messages (such as notices) sent before that.
+fn wait_for_close(mut client: Client) -> Vec<String> {
+    let deadline = Instant::now() + TIMEOUT;
+    let mut notices = vec![];
+    loop {
+        match client.wait_event(Some(deadline.saturating_duration_since(Instant::now()))) {
+            Ok(Some(Event::Message(Message::ChatMessage(text)))) => notices.push(text.into_owned()),
+            Ok(Some(_)) => {},
+            Ok(None) => panic!("{} timed out waiting to be disconnected", client.name()),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return notices,
+            Err(e) => panic!("{} lost its connection: {}", client.name(), e),
+        };
+    }
+}
+
+/// Waits for the next chat message, returning its sender and text.
+fn next_chat(client: &mut Client) -> (String, String) {
+    match wait_for(client, |msg| matches!(msg, Message::StampedChatMessage { .. })) {
+        Message::StampedChatMessage { sender, text, .. } => (sender.into_owned(), text.into_owned()),
+        _ => unreachable!(),
+    }
+}
+
+#[test]
+fn joins_and_disconnects_are_announced() {
+    let server = start_server();
+    let mut alice = connect(&server);
+    let bob = connect(&server);
+    let carol = connect(&server);
+    let (bob_name, carol_name) = (bob.name().to_owned(), carol.name().to_owned());
+    assert_ne!(alice.name(), bob_name);
+    wait_for(&mut alice, |msg| *msg == Message::UserJoined { room: DEFAULT_ROOM.into(), name: (&bob_name).into() });
+    wait_for(&mut alice, |msg| *msg == Message::UserJoined { room: DEFAULT_ROOM.into(), name: (&carol_name).into() });
+
+    bob.disconnect().unwrap();
+    wait_for(&mut alice, |msg| matches!(msg, Message::UserLeft { name, reason: LEAVE_DISCONNECTED, .. } if *name == bob_name));
+    // going away without a Disconnect
+    drop(carol);
+    wait_for(&mut alice, |msg| matches!(msg, Message::UserLeft { name, reason: LEAVE_CONNECTION_LOST, .. } if *name == carol_name));
+
+    alice.send(&Message::RosterRequest).unwrap();
+    let own_name = alice.name().to_owned();
+    wait_for(&mut alice, |msg| *msg == Message::Roster(vec![(&own_name).into()]));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn events_say_which_room_they_happened_in() {
+    let server = start_server();
+    let mut alice = connect(&server);
+    let mut bob = connect(&server);
+    bob.send(&Message::RoomJoinRequest("den".into())).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::RoomJoined("den".into()));
+    bob.change_name("bob").unwrap();
+    wait_for(&mut alice, |msg| matches!(msg, Message::UserRenamed { room, new_name, .. } if room == "den" && new_name == "bob"));
+    bob.disconnect().unwrap();
+    wait_for(&mut alice, |msg| matches!(msg, Message::UserLeft { room, name, .. } if room == "den" && name == "bob"));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn chat_goes_to_everyone_else() {
+    let server = start_server();
+    let mut clients: Vec<Client> = (0..3).map(|_| connect(&server)).collect();
+    let sender = clients[0].name().to_owned();
+    clients[0].send_chat("hello everyone").unwrap();
+    for client in &mut clients[1..] {
+        assert_eq!(next_chat(client), (sender.clone(), "hello everyone".to_owned()));
+    }
+    // the sender does not get its own message back, so the next one it sees is the reply
+    let replier = clients[1].name().to_owned();
+    clients[1].send_chat("hi").unwrap();
+    assert_eq!(next_chat(&mut clients[0]), (replier.clone(), "hi".to_owned()));
+    assert_eq!(next_chat(&mut clients[2]), (replier, "hi".to_owned()));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn renames_are_announced_and_conflicts_denied() {
+    let server = start_server();
+    let mut alice = connect(&server);
+    let mut bob = connect(&server);
+    let old_name = alice.name().to_owned();
+    alice.change_name("alice").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::NameChangeApproval);
+    assert_eq!(alice.name(), "alice");
+    wait_for(&mut bob, |msg| *msg == Message::UserRenamed { room: DEFAULT_ROOM.into(), old_name: (&old_name).into(), new_name: "alice".into() });
+
+    // names that only differ in case count as the same
+    let bob_name = bob.name().to_owned();
+    bob.change_name("Alice").unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::NameChangeDenial(NameDenial::AlreadyExists));
+    assert_eq!(bob.name(), bob_name);
+
+    alice.send_chat("it's me").unwrap();
+    assert_eq!(next_chat(&mut bob), ("alice".to_owned(), "it's me".to_owned()));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn accounts_and_moderation_are_only_offered_with_accounts() {
+    let server = start_server();
+    let client = connect(&server);
+    assert!(!client.capabilities().contains(Capabilities::ACCOUNTS));
+    assert!(!client.capabilities().contains(Capabilities::MODERATION));
+    server.shutdown().unwrap();
+
+    let accounts_file = temp_path("capability-accounts.txt");
+    let server = start_server_with(accounts_config(&accounts_file));
+    let client = connect(&server);
+    assert!(client.capabilities().contains(Capabilities::ACCOUNTS));
+    assert!(client.capabilities().contains(Capabilities::MODERATION));
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn direct_messages_find_look_alike_names() {
+    let server = start_server();
+    let mut alice = connect_as(&server, "alice");
+    let mut bob = connect_as(&server, "bob");
+    bob.send(&Message::DirectMessage { peer: "AL1CE".into(), text: "psst".into() }).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::DirectMessage { peer: "bob".into(), text: "psst".into() });
+    bob.send(&Message::DirectMessage { peer: "carol".into(), text: "psst".into() }).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::DirectMessageError { reason: DIRECT_NO_SUCH_USER, recipient: "carol".into() });
+    server.shutdown().unwrap();
+}
+
+/// With accounts kept in accounts_file, and no limit on how often names can be changed or passwords tried.
+fn accounts_config(accounts_file: &Path) -> ServerConfig {
+    let rate_limits = RateLimits { names: Limit::per_second(0, 0), passwords: Limit::per_second(0, 0), ..RateLimits::default() };
+    ServerConfig { accounts_file: Some(accounts_file.to_path_buf()), rate_limits, ..ServerConfig::default() }
+}
+
+#[test]
+fn registered_names_are_protected() {
+    let accounts_file = temp_path("accounts.txt");
+    let server = start_server_with(accounts_config(&accounts_file));
+    let mut alice = connect(&server);
+    alice.change_name("alice").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::NameChangeApproval);
+    alice.send(&Message::RegisterRequest { password: "short".into() }).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::RegisterDenial(AccountDenial::PasswordTooShort));
+    alice.send(&Message::RegisterRequest { password: "correct horse".into() }).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::RegisterApproval);
+    alice.disconnect().unwrap();
+
+    // the name, and anything that looks like it, is only for whoever logs in to it
+    let mut bob = connect(&server);
+    for name in &["alice", "AL1CE"] {
+        bob.change_name(name).unwrap();
+        wait_for(&mut bob, |msg| *msg == Message::NameChangeDenial(NameDenial::Registered));
+    }
+    bob.send(&Message::LoginRequest { name: "alice".into(), password: "battery staple".into() }).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::LoginDenial(AccountDenial::InvalidCredentials));
+    bob.send(&Message::LoginRequest { name: "Alice".into(), password: "correct horse".into() }).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::LoginApproval("alice".into()));
+    assert_eq!(bob.name(), "alice");
+    server.shutdown().unwrap();
+
+    // accounts outlive the server
+    let server = start_server_with(accounts_config(&accounts_file));
+    let mut carol = connect(&server);
+    carol.change_name("alice").unwrap();
+    wait_for(&mut carol, |msg| *msg == Message::NameChangeDenial(NameDenial::Registered));
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+#[test]
+fn logging_in_takes_over_the_account() {
+    let accounts_file = temp_path("takeover-accounts.txt");
+    let server = start_server_with(accounts_config(&accounts_file));
+    let mut first = connect_as(&server, "alice");
+    first.send(&Message::RegisterRequest { password: "correct horse".into() }).unwrap();
+    wait_for(&mut first, |msg| *msg == Message::RegisterApproval);
+    // still logged in to alice under another name
+    first.change_name("alicia").unwrap();
+    wait_for(&mut first, |msg| *msg == Message::NameChangeApproval);
+
+    let mut second = connect(&server);
+    second.send(&Message::LoginRequest { name: "alice".into(), password: "correct horse".into() }).unwrap();
+    // the other session is gone before the approval is sent
+    wait_for(&mut second, |msg| matches!(msg, Message::UserLeft { name, reason: LEAVE_REMOVED, .. } if name == "alicia"));
+    wait_for(&mut second, |msg| *msg == Message::LoginApproval("alice".into()));
+    assert!(wait_for_close(first).is_empty());
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+#[test]
+fn passwords_are_limited_per_address() {
+    let accounts_file = temp_path("limited-accounts.txt");
+    let mut config = accounts_config(&accounts_file);
+    config.rate_limits.passwords = Limit::per_minute(1, 3);
+    let server = start_server_with(config);
+    let mut alice = connect(&server);
+    let mut bob = connect(&server);
+    let login = Message::LoginRequest { name: "alice".into(), password: "correct horse".into() };
+    // one at a time for each client, however many the address may try
+    alice.send(&login).unwrap();
+    alice.send(&login).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::LoginDenial(AccountDenial::RateLimited));
+    wait_for(&mut alice, |msg| *msg == Message::LoginDenial(AccountDenial::InvalidCredentials));
+    // bob connects from the same address as alice
+    bob.send(&login).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::LoginDenial(AccountDenial::InvalidCredentials));
+    bob.change_name("bob").unwrap();
+    bob.send(&Message::RegisterRequest { password: "battery staple".into() }).unwrap();
+    wait_for(&mut bob, |msg| *msg == Message::RegisterApproval);
+    alice.send(&login).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::LoginDenial(AccountDenial::RateLimited));
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+/// With opal as an operator, once it has registered (see connect_operator).
+fn moderation_config(accounts_file: &Path) -> ServerConfig {
+    ServerConfig { operators: vec!["opal".into()], ..accounts_config(accounts_file) }
+}
+
+/// Connects a client named name.
+fn connect_as(server: &ServerHandle, name: &str) -> Client {
+    let mut client = connect(server);
+    client.change_name(name).unwrap();
+    wait_for(&mut client, |msg| *msg == Message::NameChangeApproval);
+    client
+}
+
+/// Connects a client that registers as opal, and so is an operator.
+fn connect_operator(server: &ServerHandle) -> Client {
+    let mut opal = connect_as(server, "opal");
+    opal.send(&Message::RegisterRequest { password: "correct horse".into() }).unwrap();
+    wait_for(&mut opal, |msg| *msg == Message::RegisterApproval);
+    opal
+}
+
+fn moderate(operator: &mut Client, action: ModerationAction, target: &str, duration_secs: u32, reason: &str) {
+    operator.send(&Message::ModerationRequest { action, target: target.into(), duration_secs, reason: reason.into() }).unwrap();
+}
+
+/// Waits for a notice (a v1 chat message) with exactly text.
+fn wait_for_notice(client: &mut Client, text: &str) {
+    wait_for(client, |msg| *msg == Message::ChatMessage(text.into()));
+}
+
+#[test]
+fn kicks_disconnect_the_client() {
+    let accounts_file = temp_path("kick-accounts.txt");
+    let server = start_server_with(moderation_config(&accounts_file));
+    let mut opal = connect_operator(&server);
+    let mut alice = connect_as(&server, "alice");
+    let bob = connect_as(&server, "bob");
+
+    moderate(&mut alice, ModerationAction::Kick, "bob", 0, "");
+    wait_for(&mut alice, |msg| *msg == Message::ModerationDenial(ModerationError::NotOperator));
+    moderate(&mut opal, ModerationAction::Kick, "nobody", 0, "");
+    wait_for(&mut opal, |msg| *msg == Message::ModerationDenial(ModerationError::NoSuchUser));
+
+    moderate(&mut opal, ModerationAction::Kick, "BOB", 0, "spam");
+    assert_eq!(wait_for_close(bob), ["You were kicked by opal: spam"]);
+    wait_for_notice(&mut alice, "opal kicked BOB: spam");
+    wait_for(&mut alice, |msg| matches!(msg, Message::UserLeft { name, reason: LEAVE_REMOVED, .. } if name == "bob"));
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+#[test]
+fn bans_keep_names_and_addresses_out() {
+    let accounts_file = temp_path("ban-accounts.txt");
+    let server = start_server_with(moderation_config(&accounts_file));
+    let mut opal = connect_operator(&server);
+    let mut alice = connect_as(&server, "alice");
+    let bob = connect_as(&server, "bob");
+
+    moderate(&mut opal, ModerationAction::Ban, "bob", 60, "");
+    assert_eq!(wait_for_close(bob), ["You were banned by opal for 1m"]);
+    wait_for_notice(&mut alice, "opal banned bob for 1m");
+    let mut carol = connect(&server);
+    carol.change_name("B0B").unwrap();
+    wait_for(&mut carol, |msg| *msg == Message::NameChangeDenial(NameDenial::Banned));
+    moderate(&mut opal, ModerationAction::Ban, "bob", 0, "");
+    wait_for_notice(&mut alice, "opal lifted the ban on bob");
+    carol.change_name("bob").unwrap();
+    wait_for(&mut carol, |msg| *msg == Message::NameChangeApproval);
+
+    // everyone here connects from 127.0.0.1, the operator included
+    moderate(&mut opal, ModerationAction::Ban, "127.0.0.1", 3600, "closed");
+    for client in [alice, carol, opal] {
+        let notices = wait_for_close(client);
+        assert_eq!(notices.last().map(String::as_str), Some("You were banned by opal for 1h: closed"));
+    }
+    let refused = Client::connect(server.local_addr(), None, BASE_PROTOCOL_VERSION).unwrap();
+    assert_eq!(wait_for_close(refused), ["You are banned from this server."]);
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+#[test]
+fn mutes_follow_the_name_and_account() {
+    let accounts_file = temp_path("mute-accounts.txt");
+    let server = start_server_with(moderation_config(&accounts_file));
+    let mut opal = connect_operator(&server);
+    let mut alice = connect_as(&server, "alice");
+    alice.send(&Message::RegisterRequest { password: "battery staple".into() }).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::RegisterApproval);
+    let mut bob = connect_as(&server, "bob");
+
+    moderate(&mut opal, ModerationAction::Mute, "alice", 600, "calm down");
+    wait_for_notice(&mut alice, "You were muted by opal for 10m: calm down");
+    wait_for_notice(&mut bob, "opal muted alice for 10m: calm down");
+    alice.send_chat("hi").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::ChatMessageError(ChatError::Muted));
+
+    // neither a new name, nor a new connection, nor logging in to the account gets out of it
+    alice.change_name("alicia").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::NameChangeApproval);
+    alice.send_chat("hi").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::ChatMessageError(ChatError::Muted));
+    alice.disconnect().unwrap();
+    let mut alicia = connect_as(&server, "alicia");
+    alicia.send_chat("hi").unwrap();
+    wait_for(&mut alicia, |msg| *msg == Message::ChatMessageError(ChatError::Muted));
+    alicia.disconnect().unwrap();
+    let mut alice = connect(&server);
+    alice.send(&Message::LoginRequest { name: "alice".into(), password: "battery staple".into() }).unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::LoginApproval("alice".into()));
+    alice.send_chat("hi").unwrap();
+    wait_for(&mut alice, |msg| *msg == Message::ChatMessageError(ChatError::Muted));
+
+    moderate(&mut opal, ModerationAction::Mute, "alice", 0, "");
+    wait_for_notice(&mut bob, "opal unmuted alice");
+    alice.send_chat("sorry").unwrap();
+    assert_eq!(next_chat(&mut bob), ("alice".to_owned(), "sorry".to_owned()));
+    moderate(&mut opal, ModerationAction::Mute, "alice", 0, "");
+    wait_for(&mut opal, |msg| *msg == Message::ModerationDenial(ModerationError::NoSuchUser));
+    server.shutdown().unwrap();
+    std::fs::remove_file(&accounts_file).unwrap();
+}
+
+#[test]
+fn unthrottled_floods_do_not_hold_up_the_server() {
+    let rate_limits = RateLimits { bytes: Limit::per_second(0, 0), ..RateLimits::default() };
+    let server = start_server_with(ServerConfig { rate_limits, ..ServerConfig::default() });
+    let mut alice = connect(&server);
+    let mut bob = connect(&server);
+
+    // pongs need no answer, so the flood never has to stop to read
+    let mut flood = TcpStream::connect(server.local_addr()).unwrap();
+    let pongs: Vec<u8> = (0..4096).flat_map(|i| {
+        let msg = Message::Pong(i).to_bytes();
+        (msg.len() as u32).to_le_bytes().iter().chain(&msg).copied().collect::<Vec<u8>>()
+    }).collect();
+    let stop = Arc::new(AtomicBool::new(false));
+    let flooding = {
+        let stop = Arc::clone(&stop);
+        std::thread::spawn(move || {
+            while !stop.load(Ordering::Relaxed) && flood.write_all(&pongs).is_ok() {}
+        })
+    };
+
+    for i in 0..5 {
+        let text = format!("still here {}", i);
+        alice.send_chat(&text).unwrap();
+        assert_eq!(next_chat(&mut bob), (alice.name().to_owned(), text));
+    }
+    stop.store(true, Ordering::Relaxed);
+    server.shutdown().unwrap();
+    flooding.join().unwrap();
+}
+
+#[test]
+fn shutdown_disconnects_everyone() {
+    let server = start_server();
+    let addr = server.local_addr();
+    let mut clients: Vec<Client> = (0..2).map(|_| connect(&server)).collect();
+    server.shutdown().unwrap();
+    for client in &mut clients {
+        let events: Vec<Event> = client.events().map(Result::unwrap).collect();
+        assert_eq!(events.last(), Some(&Event::Message(Message::Disconnect)));
+    }
+    assert!(Client::connect(addr, None, PROTOCOL_VERSION).is_err());
+}
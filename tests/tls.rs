@@ -0,0 +1,182 @@
+//! Runs a TLS server in-process, with the certificates in tests/fixtures (see generate.sh there),
+//! and checks that clients can talk to it, and trust it only when they should.
+
+use std::io::{self, prelude::*};
+use std::net::{IpAddr, TcpListener, TcpStream};
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+use chatapp::client::{Client, Event};
+use chatapp::messages::*;
+use chatapp::server::{Server, ServerConfig, ServerHandle};
+use chatapp::tls::{server_config, ClientTls, Stream};
+use chatapp::util::{poll_events, FrameDecoder, FrameEncoder};
+use libc::{POLLIN, POLLOUT};
+
+/// How long to wait for something that should happen right away.
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn fixture(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures").join(name)
+}
+
+/// Serves TLS with the certificate in fixtures/{cert}.pem and its key.
+fn start_server(cert: &str) -> ServerHandle {
+    chatapp::set_level(chatapp::Level::Off);
+    let tls = server_config(&fixture(&format!("{}.pem", cert)), &fixture(&format!("{}.key", cert))).unwrap();
+    let config = ServerConfig { tls: Some(tls), ..ServerConfig::default() };
+    Server::bind("127.0.0.1:0".parse().unwrap(), config).unwrap().spawn().unwrap()
+}
+
+/// Trusts servers with a certificate for 127.0.0.1 from the test CA.
+fn ca_tls() -> ClientTls {
+    let localhost: IpAddr = "127.0.0.1".parse().unwrap();
+    ClientTls::with_ca(&fixture("ca.pem"), localhost.into()).unwrap()
+}
+
+/// A known hosts file for a test, unique to the test, with nothing in it yet.
+fn temp_known_hosts(name: &str) -> PathBuf {
+    let path = std::env::temp_dir().join(format!("chatapp-tls-{}-{}", std::process::id(), name));
+    let _ = std::fs::remove_file(&path);
+    path
+}
+
+/// Connects a client, and waits until the server has welcomed it.
+fn connect(server: &ServerHandle, tls: &ClientTls) -> io::Result<Client> {
+    let mut client = Client::connect(server.local_addr(), Some(tls), PROTOCOL_VERSION)?;
+    wait_for(&mut client, |msg| matches!(msg, Message::Welcome { .. }));
+    Ok(client)
+}
+
+/// Waits for the first message that matches, skipping any others.
+fn wait_for(client: &mut Client, mut matches: impl FnMut(&Message) -> bool) -> Message<'static> {
+    let deadline = Instant::now() + TIMEOUT;
+    loop {
+        match client.wait_event(Some(deadline.saturating_duration_since(Instant::now()))) {
+            Ok(Some(Event::Message(msg))) if matches(&msg) => return msg,
+            Ok(Some(_)) => {},
+            Ok(None) => panic!("{} timed out waiting for a message", client.name()),
+            Err(e) => panic!("{} lost its connection: {}", client.name(), e),
+        };
+    }
+}
+
+#[test]
+fn clients_trust_certificates_from_their_ca() {
+    let server = start_server("server");
+    let tls = ca_tls();
+    let mut alice = connect(&server, &tls).unwrap();
+    let mut bob = connect(&server, &tls).unwrap();
+    alice.send_chat("over TLS").unwrap();
+    let chat = wait_for(&mut bob, |msg| matches!(msg, Message::StampedChatMessage { .. }));
+    assert!(matches!(chat, Message::StampedChatMessage { text, .. } if text == "over TLS"));
+    server.shutdown().unwrap();
+
+    // the CA did not issue this one
+    let server = start_server("other");
+    let err = Client::connect(server.local_addr(), Some(&tls), PROTOCOL_VERSION).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    server.shutdown().unwrap();
+}
+
+#[test]
+fn certificates_are_trusted_on_first_use() {
+    let known_hosts = temp_known_hosts("known_hosts");
+    let server = start_server("server");
+    let addr = server.local_addr();
+    let tls = ClientTls::trust_on_first_use(known_hosts.clone(), addr);
+    connect(&server, &tls).unwrap().disconnect().unwrap();
+    let fingerprint = tls.take_newly_trusted().unwrap();
+    assert_eq!(std::fs::read_to_string(&known_hosts).unwrap(), format!("{} {}\n", addr, fingerprint));
+
+    // the same certificate again
+    connect(&server, &tls).unwrap().disconnect().unwrap();
+    assert_eq!(tls.take_newly_trusted(), None);
+    server.shutdown().unwrap();
+
+    // another certificate at an address that had the first one
+    let server = start_server("other");
+    let addr = server.local_addr();
+    std::fs::write(&known_hosts, format!("{} {}\n", addr, fingerprint)).unwrap();
+    let tls = ClientTls::trust_on_first_use(known_hosts.clone(), addr);
+    let err = Client::connect(addr, Some(&tls), PROTOCOL_VERSION).err().unwrap();
+    assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    assert!(err.to_string().starts_with(&format!("the certificate of {} has changed", addr)), "{}", err);
+    assert_eq!(tls.take_newly_trusted(), None);
+    server.shutdown().unwrap();
+    std::fs::remove_file(&known_hosts).unwrap();
+}
+
+/// Waits until fd is ready for events.
+fn wait_ready(stream: &Stream, events: i16) {
+    let ready = poll_events(std::iter::once(((), stream.as_raw_fd(), events)), TIMEOUT.as_millis() as i32).unwrap();
+    assert!(!ready.is_empty(), "timed out waiting for the socket");
+}
+
+#[test]
+fn tls_streams_never_block() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let client_sock = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let (server_sock, _) = listener.accept().unwrap();
+    client_sock.set_nonblocking(true).unwrap();
+    server_sock.set_nonblocking(true).unwrap();
+    let config = server_config(&fixture("server.pem"), &fixture("server.key")).unwrap();
+    let mut server = Stream::server(server_sock, &config).unwrap();
+    let mut client = ca_tls().connect(client_sock).unwrap();
+    // the server has not even been sent the client hello yet
+    assert_eq!(client.read(&mut [0; 16]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+
+    // more than the sockets hold, with nobody reading for a while
+    let big_frame: Vec<u8> = (0..8 << 20).map(|i| i as u8).collect();
+    let mut encoder = FrameEncoder::new();
+    encoder.push(&big_frame);
+    encoder.push(b"after");
+    let mut decoder = FrameDecoder::new(u32::MAX);
+    // the server sends nothing but its part of the handshake, which the client only takes in when reading
+    let mut replies = FrameDecoder::new(u32::MAX);
+    let mut blocked = false;
+    let deadline = Instant::now() + TIMEOUT;
+    while !encoder.is_empty() || client.wants_write() {
+        assert!(Instant::now() < deadline, "timed out writing");
+        encoder.write_to(&mut client).unwrap();
+        blocked |= !encoder.is_empty() || client.wants_write();
+        decoder.read_from(&mut server).unwrap();
+        replies.read_from(&mut client).unwrap();
+    }
+    assert_eq!(replies.buffered_len(), 0);
+    assert!(blocked);
+    let mut frames = vec![];
+    while frames.len() < 2 {
+        assert!(Instant::now() < deadline, "timed out reading");
+        match decoder.next_frame().unwrap() {
+            Some(frame) => frames.push(frame),
+            None => {
+                wait_ready(&server, POLLIN);
+                decoder.read_from(&mut server).unwrap();
+            },
+        }
+    }
+    assert!(frames[0] == big_frame);
+    assert_eq!(frames[1], b"after");
+
+    // what was decrypted but not read yet is no longer in the socket, so poll does not see it
+    let mut encoder = FrameEncoder::new();
+    encoder.push(b"hello");
+    while !encoder.is_empty() || client.wants_write() {
+        wait_ready(&client, POLLOUT);
+        encoder.write_to(&mut client).unwrap();
+    }
+    wait_ready(&server, POLLIN);
+    let mut decoder = FrameDecoder::new(u32::MAX);
+    while decoder.buffered_len() == 0 {
+        decoder.read_at_most(&mut server, 1).unwrap();
+    }
+    assert!(server.has_buffered_input());
+    assert!(poll_events(std::iter::once(((), server.as_raw_fd(), POLLIN)), 0).unwrap().is_empty());
+    while server.has_buffered_input() {
+        decoder.read_at_most(&mut server, 1).unwrap();
+    }
+    assert_eq!(decoder.next_frame().unwrap(), Some(b"hello".to_vec()));
+    assert_eq!(server.read(&mut [0; 16]).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+}